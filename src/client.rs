@@ -1,8 +1,238 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use std::fmt::Debug;
 use std::fs;
-use tokio::time::{sleep, Duration};
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Duration};
 use tokio_modbus::prelude::*;
+use tokio_util::codec::Framed;
 
-use crate::{config::*, data::*, types::*, util::*};
+use crate::{config::*, data::*, frame::*, identification::*, types::*, util::*};
+
+/// Modbus client over the framing of yams, as tokio-modbus doesn't support Modbus ASCII
+/// and fails to tell the length of some RTU responses
+#[derive(Debug)]
+struct FramedClient<T> {
+    framed: Framed<T, AduCodec>,
+    framing: Framing,
+    slave: Slave,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> FramedClient<T> {
+    fn new(transport: T, framing: Framing, slave: Slave) -> Self {
+        FramedClient {
+            framed: Framed::new(transport, AduCodec::client(framing)),
+            framing,
+            slave,
+        }
+    }
+}
+
+impl<T> SlaveContext for FramedClient<T> {
+    fn set_slave(&mut self, slave: Slave) {
+        self.slave = slave;
+    }
+}
+
+#[async_trait]
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Debug> Client for FramedClient<T> {
+    async fn call(&mut self, req: Request) -> Result<Response, Error> {
+        let unit_id = self.slave.0;
+        let header = match self.framing {
+            Framing::Ascii => AduHeader::Ascii { unit_id },
+            _ => AduHeader::Rtu { unit_id },
+        };
+        self.framed
+            .send(Adu {
+                header,
+                pdu: Bytes::from(req),
+            })
+            .await?;
+        let rsp = self
+            .framed
+            .next()
+            .await
+            .unwrap_or_else(|| Err(Error::from(ErrorKind::BrokenPipe)))?;
+        if rsp.header != header {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid response header: {:?}", rsp.header),
+            ));
+        }
+        match rsp.pdu.first() {
+            Some(f) if f & 0x80 != 0 => {
+                let code = rsp
+                    .pdu
+                    .get(1)
+                    .copied()
+                    .unwrap_or_default();
+                Err(Error::other(match ModbusExceptionCode::try_from(code) {
+                    Ok(e) => format!("Modbus function {}: {:?} exception", f & 0x7F, e),
+                    Err(code) => format!("Modbus function {}: exception {:#04X}", f & 0x7F, code),
+                }))
+            }
+            _ => Response::try_from(rsp.pdu),
+        }
+    }
+}
+
+/// how long to wait for a response that isn't expected
+const LISTEN_ONLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// prints the responses tokio-modbus doesn't know about
+fn print_response(response: Response) {
+    let (function_code, data) = match response {
+        Response::Custom(function_code, data) => (function_code, data),
+        response => {
+            println!("===> {:?}", response);
+            return;
+        }
+    };
+    let word_at = |i: usize| {
+        data.get(i..i + 2)
+            .map(|w| u16::from_be_bytes([w[0], w[1]]))
+            .unwrap_or_default()
+    };
+    match function_code {
+        0x07 => println!("===> exception status {:#010b}", data.first().unwrap_or(&0)),
+        0x08 => match DiagnosticsSubFunction::try_from(word_at(0)) {
+            Ok(
+                s @ (DiagnosticsSubFunction::ReturnQueryData
+                | DiagnosticsSubFunction::RestartCommunications
+                | DiagnosticsSubFunction::ClearCounters
+                | DiagnosticsSubFunction::ClearOverrunCounter),
+            ) => println!("===> {:?} {:02X?}", s, &data[2..]),
+            Ok(s) => println!("===> {:?}: {} ({:#06X})", s, word_at(2), word_at(2)),
+            Err(s) => println!("===> sub function {:#06X}: {:02X?}", s, &data[2..]),
+        },
+        0x0B => println!(
+            "===> status {:#06X}, event count {}",
+            word_at(0),
+            word_at(2)
+        ),
+        0x2B => {
+            let (objects, _, _) = parse_objects(&data);
+            match ReadDeviceIdCode::try_from(data.get(1).copied().unwrap_or_default()) {
+                Ok(code) => print!("===> {:?} device identification", code),
+                Err(code) => print!("===> device identification ({:#04X})", code),
+            }
+            println!(
+                ", conformity level {:#04X}",
+                data.get(2).copied().unwrap_or_default()
+            );
+            for (id, value) in objects {
+                match OBJECT_NAMES.get(id as usize) {
+                    Some(name) => println!("     {}: {}", name, value),
+                    None => println!("     object {:#04X}: {}", id, value),
+                }
+            }
+        }
+        0x11 => match data
+            .get(1..)
+            .unwrap_or_default()
+            .split_last()
+        {
+            /* the run indicator status follows the server ID */
+            Some((status @ (0x00 | 0xFF), server_id)) => println!(
+                "===> server ID {:02X?}, run indicator {}",
+                server_id,
+                if *status == 0xFF { "ON" } else { "OFF" }
+            ),
+            _ => println!("===> server ID {:02X?}", data.get(1..).unwrap_or_default()),
+        },
+        0x18 => {
+            let values: Vec<u16> = data
+                .get(4..)
+                .unwrap_or_default()
+                .chunks_exact(2)
+                .map(|w| u16::from_be_bytes([w[0], w[1]]))
+                .collect();
+            println!("===> FIFO count {}, values {:04X?}", word_at(2), values);
+        }
+        0x16 => println!(
+            "===> register {} masked with AND {:#06X} OR {:#06X}",
+            word_at(0),
+            word_at(2),
+            word_at(4)
+        ),
+        0x0C => println!(
+            "===> status {:#06X}, event count {}, message count {}, events {:02X?}",
+            word_at(1),
+            word_at(3),
+            word_at(5),
+            data.get(7..).unwrap_or_default()
+        ),
+        _ => println!("===> {:02X?}", data),
+    }
+}
+
+/// the request data of Read/Write File Record holding a single sub-request,
+/// along with the records to write if any
+fn file_record_sub_request(
+    file_number: u16,
+    record_number: u16,
+    record_length: u16,
+    records: &[u16],
+) -> Vec<u8> {
+    /* the reference type is always 6 */
+    let mut pdu = vec![(7 + records.len() * 2) as u8, 0x06];
+    pdu.extend_from_slice(&file_number.to_be_bytes());
+    pdu.extend_from_slice(&record_number.to_be_bytes());
+    pdu.extend_from_slice(&record_length.to_be_bytes());
+    records
+        .iter()
+        .for_each(|r| pdu.extend_from_slice(&r.to_be_bytes()));
+    pdu
+}
+
+/// the records of a Read File Record response, walking its sub-responses each made of
+/// the file response length, the reference type and the records
+fn file_record_response_records(data: &[u8]) -> Option<Vec<u16>> {
+    let byte_count = *data.first()? as usize;
+    let mut rest = data.get(1..1 + byte_count)?;
+    let mut records = Vec::new();
+    while !rest.is_empty() {
+        let len = rest[0] as usize;
+        let sub_response = rest.get(1..1 + len)?;
+        /* the reference type is always 6 */
+        let words = sub_response
+            .get(1..)
+            .filter(|_| sub_response[0] == 0x06)?
+            .chunks_exact(2);
+        if !words.remainder().is_empty() {
+            return None;
+        }
+        records.extend(words.map(|w| u16::from_be_bytes([w[0], w[1]])));
+        rest = &rest[1 + len..];
+    }
+    Some(records)
+}
+
+/// encodes the values of a write request into registers
+fn write_values_into_u16(
+    new_values: Vec<String>,
+    r: &ModbusRequest,
+    e: EndiannessType,
+) -> Vec<u16> {
+    let mut data = Vec::<u16>::new();
+    for v in new_values {
+        let mut d = ModbusRegisterData::new(
+            r.data_type
+                .expect("missing data type for write"),
+            v,
+        );
+        d.data_length = r.data_length;
+        d.scale = r.scale;
+        d.offset = r.offset;
+        if d.write_into_u16(&mut data, e) == 0 {
+            println!("failed in encoding {} as {:?}", d.data_value, d.data_type);
+        }
+    }
+    data
+}
 
 pub async fn start_modbus_client(
     mut config: ModbusDeviceConfig,
@@ -33,14 +263,30 @@ pub async fn start_modbus_client(
                     .expect("Server IP address missing in config");
                 tcp::connect_slave(ip_addr, server).await?
             }
-            ProtocolType::RTU => {
-                let serial = build_serial(&config).ok_or("failed in building the serial client")?;
-                rtu::connect_slave(serial, server).await?
+            ProtocolType::RtuOverTcp => {
+                let ip_addr = request
+                    .server_address
+                    .take()
+                    .expect("Server IP address missing in config");
+                let stream = TcpStream::connect(ip_addr).await?;
+                let client: Box<dyn Client> =
+                    Box::new(FramedClient::new(stream, Framing::Rtu, server));
+                client::Context::from(client)
+            }
+            ProtocolType::RTU | ProtocolType::Ascii => {
+                let serial = build_serial(&config.common.default_endpoint())
+                    .ok_or("failed in building the serial client")?;
+                let framing = match config.common.protocol_type {
+                    ProtocolType::Ascii => Framing::Ascii,
+                    _ => Framing::Rtu,
+                };
+                let client: Box<dyn Client> = Box::new(FramedClient::new(serial, framing, server));
+                client::Context::from(client)
             }
         };
         let mut rlist = Vec::<ModbusRequest>::new();
         for request_file in &request.request_files {
-            if let Ok(request_str) = fs::read_to_string(&request_file) {
+            if let Ok(request_str) = fs::read_to_string(request_file) {
                 if let Ok(r) = serde_yaml::from_str(&request_str) {
                     rlist.push(r);
                 } else {
@@ -54,10 +300,7 @@ pub async fn start_modbus_client(
             rlist.push(r);
         }
 
-        let mut section_repeat_times = request
-            .repeat_times
-            .or_else(|| Some(1))
-            .unwrap();
+        let mut section_repeat_times = request.repeat_times.unwrap_or(1);
         #[allow(unused_parens)]
         let section_indefinite_loop = (section_repeat_times == REPEAT_TIME_INDEFINITE);
         while section_repeat_times > 0 {
@@ -67,13 +310,11 @@ pub async fn start_modbus_client(
             for r in &mut rlist {
                 let start_addr = r.access_start_address;
                 let count = r.access_quantity;
-                let mut file_repeat_times = r
-                    .repeat_times
-                    .or_else(|| Some(1))
-                    .unwrap();
+                let endianness = r.endianness.unwrap_or(endianness);
+                let mut file_repeat_times = r.repeat_times.unwrap_or(1);
                 #[allow(unused_parens)]
                 let file_indefinite_loop = (file_repeat_times == REPEAT_TIME_INDEFINITE);
-                let delay_in_100ms = r.delay.or_else(|| Some(0)).unwrap();
+                let delay_in_100ms = r.delay.unwrap_or(0);
                 while file_repeat_times > 0 {
                     if !file_indefinite_loop {
                         file_repeat_times -= 1;
@@ -116,19 +357,7 @@ pub async fn start_modbus_client(
                                 .new_values
                                 .take()
                                 .expect("missing value for write");
-                            let mut data = Vec::<u16>::new();
-                            for v in new_values {
-                                let d = ModbusRegisterData {
-                                    data_description: "".to_string(),
-                                    data_model_type: DataModelType::HoldingOrInputRegister,
-                                    data_access_type: None,
-                                    data_type: r
-                                        .data_type
-                                        .expect("missing data type for write"),
-                                    data_value: v,
-                                };
-                                d.write_into_u16(&mut data, endianness);
-                            }
+                            let data = write_values_into_u16(new_values, r, endianness);
                             vprintln(
                                 &format!(
                                     "writing registers starting at {} with values:",
@@ -147,19 +376,7 @@ pub async fn start_modbus_client(
                                 .new_values
                                 .take()
                                 .expect("missing value for write");
-                            let mut data = Vec::<u16>::new();
-                            for v in new_values {
-                                let d = ModbusRegisterData {
-                                    data_description: "".to_string(),
-                                    data_model_type: DataModelType::HoldingOrInputRegister,
-                                    data_access_type: None,
-                                    data_type: r
-                                        .data_type
-                                        .expect("missing data type for write"),
-                                    data_value: v,
-                                };
-                                d.write_into_u16(&mut data, endianness);
-                            }
+                            let data = write_values_into_u16(new_values, r, endianness);
                             vprintln(
                                 &format!("writing register at {} with value:", start_addr),
                                 config.verbose_mode,
@@ -170,24 +387,184 @@ pub async fn start_modbus_client(
                                     .await,
                             )
                         }
-                        FunctionCode::ReadWriteMultipleRegisters => {
+                        FunctionCode::MaskWriteRegister => {
+                            let masks: Vec<u16> = r
+                                .new_values
+                                .as_ref()
+                                .expect("missing AND/OR masks for mask write")
+                                .iter()
+                                .map(|v| {
+                                    parse_int::parse::<u16>(v)
+                                        .expect("incorrect value for mask write")
+                                })
+                                .collect();
+                            let [and_mask, or_mask] = masks[..] else {
+                                panic!("mask write takes an AND mask and an OR mask");
+                            };
+                            vprintln(
+                                &format!(
+                                    "masking register at {} with AND {:#06X} OR {:#06X}",
+                                    start_addr, and_mask, or_mask
+                                ),
+                                config.verbose_mode,
+                            );
+                            let mut pdu = start_addr.to_be_bytes().to_vec();
+                            pdu.extend_from_slice(&and_mask.to_be_bytes());
+                            pdu.extend_from_slice(&or_mask.to_be_bytes());
+                            ModbusRequestReturnType::ResultWithResponse(
+                                ctx.call(Request::Custom(r.function_code as u8, pdu))
+                                    .await,
+                            )
+                        }
+                        FunctionCode::Custom => {
+                            let function_code = r
+                                .custom_function_code
+                                .expect("missing function code for custom request");
+                            let data: Vec<u8> = r
+                                .new_values
+                                .iter()
+                                .flatten()
+                                .flat_map(|v| {
+                                    parse_bytes(v).expect("incorrect hex value for custom request")
+                                })
+                                .collect();
+                            vprintln(
+                                &format!(
+                                    "sending function code {:#04X} with data {:02X?}",
+                                    function_code, data
+                                ),
+                                config.verbose_mode,
+                            );
+                            ModbusRequestReturnType::ResultWithResponse(
+                                ctx.call(Request::Custom(function_code, data))
+                                    .await,
+                            )
+                        }
+                        FunctionCode::ReadFileRecord => {
+                            let file_number = r
+                                .file_number
+                                .expect("missing file number for file record");
+                            vprintln(
+                                &format!(
+                                    "reading {} records of file {} starting at {}",
+                                    count, file_number, start_addr
+                                ),
+                                config.verbose_mode,
+                            );
+                            let pdu = file_record_sub_request(file_number, start_addr, count, &[]);
+                            let response = ctx
+                                .call(Request::Custom(r.function_code as u8, pdu))
+                                .await;
+                            ModbusRequestReturnType::ResultWithU16Vec(response.and_then(|rsp| {
+                                match rsp {
+                                    Response::Custom(_, ref data) => {
+                                        file_record_response_records(data).ok_or_else(|| {
+                                            Error::new(
+                                                ErrorKind::InvalidData,
+                                                format!("unexpected response {:?}", rsp),
+                                            )
+                                        })
+                                    }
+                                    rsp => Err(Error::new(
+                                        ErrorKind::InvalidData,
+                                        format!("unexpected response {:?}", rsp),
+                                    )),
+                                }
+                            }))
+                        }
+                        FunctionCode::WriteFileRecord => {
+                            let file_number = r
+                                .file_number
+                                .expect("missing file number for file record");
                             let new_values = r
                                 .new_values
                                 .take()
                                 .expect("missing value for write");
-                            let mut data = Vec::<u16>::new();
-                            for v in new_values {
-                                let d = ModbusRegisterData {
-                                    data_description: "".to_string(),
-                                    data_model_type: DataModelType::HoldingOrInputRegister,
-                                    data_access_type: None,
-                                    data_type: r
-                                        .data_type
-                                        .expect("missing data type for write"),
-                                    data_value: v,
+                            let data = write_values_into_u16(new_values, r, endianness);
+                            vprintln(
+                                &format!(
+                                    "writing records of file {} starting at {} with values:",
+                                    file_number, start_addr
+                                ),
+                                config.verbose_mode,
+                            );
+                            vprintln(&format!("{:?}", &data), config.verbose_mode);
+                            let pdu = file_record_sub_request(
+                                file_number,
+                                start_addr,
+                                data.len() as u16,
+                                &data,
+                            );
+                            ModbusRequestReturnType::ResultWithNothing(
+                                ctx.call(Request::Custom(r.function_code as u8, pdu))
+                                    .await
+                                    .map(|_| ()),
+                            )
+                        }
+                        FunctionCode::ReadFifoQueue => {
+                            vprintln(
+                                &format!("reading FIFO queue at {}", start_addr),
+                                config.verbose_mode,
+                            );
+                            ModbusRequestReturnType::ResultWithResponse(
+                                ctx.call(Request::Custom(
+                                    r.function_code as u8,
+                                    start_addr.to_be_bytes().to_vec(),
+                                ))
+                                .await,
+                            )
+                        }
+                        FunctionCode::ReadDeviceIdentification => {
+                            let read_code = r
+                                .read_device_id_code
+                                .expect("missing read device id code for device identification");
+                            let mut object_id = start_addr as u8;
+                            vprintln(
+                                &format!(
+                                    "reading {:?} device identification from object {:#04X}",
+                                    read_code, object_id
+                                ),
+                                config.verbose_mode,
+                            );
+                            /* follow the stream until the server has no more objects */
+                            let mut objects = Vec::<u8>::new();
+                            let mut object_count = 0_u8;
+                            let response = loop {
+                                let pdu = vec![MEI_READ_DEVICE_ID, read_code as u8, object_id];
+                                let data = match ctx
+                                    .call(Request::Custom(r.function_code as u8, pdu))
+                                    .await
+                                {
+                                    Ok(Response::Custom(_, data)) if data.len() >= 6 => data,
+                                    Ok(response) => break Ok(response),
+                                    Err(e) => break Err(e),
                                 };
-                                d.write_into_u16(&mut data, endianness);
-                            }
+                                objects.extend_from_slice(&data[6..]);
+                                object_count = object_count.wrapping_add(data[5]);
+                                let (_, more_follows, next_object_id) = parse_objects(&data);
+                                /* a server not moving on to a later object would be followed
+                                 * forever, hence stop there with the objects so far */
+                                if !more_follows
+                                    || read_code == ReadDeviceIdCode::Specific
+                                    || next_object_id <= object_id
+                                {
+                                    let mut data = data[..6].to_vec();
+                                    data[3] = 0x00;
+                                    data[4] = 0x00;
+                                    data[5] = object_count;
+                                    data.append(&mut objects);
+                                    break Ok(Response::Custom(r.function_code as u8, data));
+                                }
+                                object_id = next_object_id;
+                            };
+                            ModbusRequestReturnType::ResultWithResponse(response)
+                        }
+                        FunctionCode::ReadWriteMultipleRegisters => {
+                            let new_values = r
+                                .new_values
+                                .take()
+                                .expect("missing value for write");
+                            let data = write_values_into_u16(new_values, r, endianness);
                             vprintln(
                                 &format!(
                                     "writing and read registers starting at {} with values:",
@@ -264,7 +641,63 @@ pub async fn start_modbus_client(
                                     .await,
                             )
                         }
-                        _ => todo!(),
+                        FunctionCode::ReadExceptionStatus => {
+                            vprintln("reading exception status", config.verbose_mode);
+                            ModbusRequestReturnType::ResultWithResponse(
+                                ctx.call(Request::Custom(r.function_code as u8, vec![]))
+                                    .await,
+                            )
+                        }
+                        FunctionCode::Diagnostics => {
+                            let sub_function = r
+                                .sub_function
+                                .expect("missing sub function for diagnostics");
+                            let data = r
+                                .new_values
+                                .as_ref()
+                                .and_then(|v| v.first())
+                                .map(|v| {
+                                    parse_int::parse::<u16>(v)
+                                        .expect("incorrect value for diagnostics data")
+                                })
+                                .unwrap_or(0);
+                            vprintln(
+                                &format!("diagnosing {:?} with data {:#06X}", sub_function, data),
+                                config.verbose_mode,
+                            );
+                            let mut pdu = (sub_function as u16)
+                                .to_be_bytes()
+                                .to_vec();
+                            pdu.extend_from_slice(&data.to_be_bytes());
+                            let call = ctx.call(Request::Custom(r.function_code as u8, pdu));
+                            match sub_function {
+                                /* the server doesn't respond in listen only mode */
+                                DiagnosticsSubFunction::ForceListenOnlyMode
+                                | DiagnosticsSubFunction::RestartCommunications => {
+                                    match timeout(LISTEN_ONLY_TIMEOUT, call).await {
+                                        Ok(response) => {
+                                            ModbusRequestReturnType::ResultWithResponse(response)
+                                        }
+                                        Err(_) => {
+                                            ModbusRequestReturnType::ResultWithNothing(Ok(()))
+                                        }
+                                    }
+                                }
+                                _ => ModbusRequestReturnType::ResultWithResponse(call.await),
+                            }
+                        }
+                        FunctionCode::GetCommeventCounter
+                        | FunctionCode::GetcommEventLog
+                        | FunctionCode::ReportServerId => {
+                            vprintln(
+                                &format!("getting {:?}", r.function_code),
+                                config.verbose_mode,
+                            );
+                            ModbusRequestReturnType::ResultWithResponse(
+                                ctx.call(Request::Custom(r.function_code as u8, vec![]))
+                                    .await,
+                            )
+                        }
                     };
                     println!("{}", r.description);
                     match response {
@@ -273,23 +706,72 @@ pub async fn start_modbus_client(
                                 .data_type
                                 .as_ref()
                                 .expect("missing data type for write");
-                            match data_type {
-                                DataType::Float32 => println!(
-                                    "===> {:?}",
-                                    write_u16_into_f32(response.as_slice(), endianness)
-                                ),
-                                DataType::Float64 => {
-                                    println!("===> {:?}", write_u16_into_f64(&response, endianness))
-                                }
-                                DataType::Uint32 => {
-                                    let data = write_u16_into_u32(&response, endianness);
-                                    println!("===> {:?} ({:#010X})", data, data);
+                            if r.scale.is_some() || r.offset.is_some() || r.unit.is_some() {
+                                let mut d = ModbusRegisterData::new(*data_type, String::new());
+                                d.data_length = Some(response.len() as u16);
+                                d.scale = r.scale;
+                                d.offset = r.offset;
+                                d.unit = r.unit.clone();
+                                d.read_from_u16(&mut response.iter().peekable(), endianness);
+                                println!("===> {} ({:#06X?})", d.display_value(), response);
+                            } else {
+                                match data_type {
+                                    DataType::Float32 => println!(
+                                        "===> {:?}",
+                                        write_u16_into_f32(response.as_slice(), endianness)
+                                    ),
+                                    DataType::Float64 => {
+                                        let data = write_u16_into_f64(&response, endianness);
+                                        println!("===> {:?}", data);
+                                    }
+                                    DataType::Uint32 => {
+                                        let data = write_u16_into_u32(&response, endianness);
+                                        println!("===> {:?} ({:#010X})", data, data);
+                                    }
+                                    DataType::Uint64 => {
+                                        let data = write_u16_into_u64(&response, endianness);
+                                        println!("===> {:?} ({:#018X})", data, data);
+                                    }
+                                    DataType::Int32 => {
+                                        let data = write_u16_into_i32(&response, endianness);
+                                        println!("===> {:?} ({:#010X})", data, data);
+                                    }
+                                    DataType::Int64 => {
+                                        let data = write_u16_into_i64(&response, endianness);
+                                        println!("===> {:?} ({:#018X})", data, data);
+                                    }
+                                    DataType::Uint16 => {
+                                        let data = reorder_u16(&response[..1], endianness)[0];
+                                        println!("===> {:?} ({:#06X})", data, data);
+                                    }
+                                    DataType::Int16 => {
+                                        let data = write_u16_into_i16(&response, endianness);
+                                        println!("===> {:?} ({:#06X})", data, data);
+                                    }
+                                    DataType::Uint8Pair => {
+                                        let data = write_u16_into_u8_pair(&response, endianness);
+                                        println!("===> {:?} ({:#04X?})", data, data);
+                                    }
+                                    DataType::Int8Pair => {
+                                        let (first, second) =
+                                            write_u16_into_u8_pair(&response, endianness);
+                                        let data = (first as i8, second as i8);
+                                        println!("===> {:?} ({:#04X?})", data, (first, second));
+                                    }
+                                    DataType::String => {
+                                        let data = write_u16_into_string(&response, endianness);
+                                        println!("===> {:?}", data);
+                                    }
+                                    DataType::Bytes => {
+                                        let data = write_u16_into_bytes(&response, endianness);
+                                        println!("===> {}", format_bytes(&data));
+                                    }
+                                    DataType::BitField => {
+                                        let n = response.len().min(4);
+                                        let data = write_u16_into_bits(&response[..n], endianness);
+                                        println!("===> {:#0w$b}", data, w = 16 * n + 2);
+                                    }
                                 }
-                                DataType::Uint16 => {
-                                    let data = response[0];
-                                    println!("===> {:?} ({:#06X})", data, data);
-                                }
-                                _ => todo!(),
                             }
                         }
                         ModbusRequestReturnType::ResultWithBoolVec(Ok(response)) => {
@@ -298,9 +780,13 @@ pub async fn start_modbus_client(
                         ModbusRequestReturnType::ResultWithNothing(Ok(())) => {
                             println!("===> done");
                         }
+                        ModbusRequestReturnType::ResultWithResponse(Ok(response)) => {
+                            print_response(response);
+                        }
                         ModbusRequestReturnType::ResultWithNothing(Err(e))
                         | ModbusRequestReturnType::ResultWithU16Vec(Err(e))
-                        | ModbusRequestReturnType::ResultWithBoolVec(Err(e)) => {
+                        | ModbusRequestReturnType::ResultWithBoolVec(Err(e))
+                        | ModbusRequestReturnType::ResultWithResponse(Err(e)) => {
                             vprint("failure ", ansi_term::Colour::Red, true);
                             println!("{}", e);
                         }
@@ -311,3 +797,29 @@ pub async fn start_modbus_client(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_sub_responses_then_collect_their_records() {
+        let data = [
+            0x0C, 0x05, 0x06, 0x0D, 0xFE, 0x00, 0x20, 0x05, 0x06, 0x33, 0xCD, 0x00, 0x40,
+        ];
+        assert_eq!(
+            Some(vec![0x0DFE, 0x0020, 0x33CD, 0x0040]),
+            file_record_response_records(&data)
+        );
+        assert_eq!(Some(vec![]), file_record_response_records(&[0x00]));
+        assert_eq!(None, file_record_response_records(&data[..12]));
+        assert_eq!(
+            None,
+            file_record_response_records(&[0x03, 0x02, 0x06, 0x01])
+        );
+        assert_eq!(
+            None,
+            file_record_response_records(&[0x04, 0x03, 0x07, 0x00, 0x01])
+        );
+    }
+}
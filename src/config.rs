@@ -1,8 +1,11 @@
-use crate::{data::*, types::*};
+use crate::{
+    data::*, diagnostics::*, fault::*, file_record::*, helper::*, identification::*, latency::*,
+    model::*, script::*, types::*,
+};
 use anyhow::{self, Context};
 use clap::Parser;
 use serde::{Deserialize, Serialize};
-use std::{fs, net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, fs, net::SocketAddr, path::PathBuf};
 use tokio_serial::{SerialPort, SerialStream};
 
 #[derive(Parser, Debug)]
@@ -25,23 +28,29 @@ pub struct Opts {
     /// the id of the client/server
     #[clap(short('i'), long, required_unless_present("config-file"))]
     pub device_id: Option<u8>,
-    /// the socket address when using Modbus TCP
-    #[clap(short('a'), long, required_if_eq("protocol-type", "tcp"))]
+    /// the socket address when using Modbus TCP or RTU over TCP
+    #[clap(short('a'), long, required_if_eq_any(&[("protocol-type", "tcp"),
+                                                  ("protocol-type", "rtu-over-tcp")]))]
     pub ip_address: Option<SocketAddr>,
-    /// the serial port when using Modbus RTU
-    #[clap(short('s'), long, required_if_eq("protocol-type", "rtu"))]
+    /// the serial port when using Modbus RTU or ASCII
+    #[clap(short('s'), long, required_if_eq_any(&[("protocol-type", "rtu"),
+                                                  ("protocol-type", "ascii")]))]
     pub serial_port: Option<String>,
-    /// the baudrate when using Modbus RTU
-    #[clap(short('b'), long, required_if_eq("protocol-type", "rtu"))]
+    /// the baudrate when using Modbus RTU or ASCII
+    #[clap(short('b'), long, required_if_eq_any(&[("protocol-type", "rtu"),
+                                                  ("protocol-type", "ascii")]))]
     pub serial_baudrate: Option<u32>,
     /// the parity of the serial port
-    #[clap(arg_enum, short('r'), long, required_if_eq("protocol-type", "rtu"))]
+    #[clap(arg_enum, short('r'), long, required_if_eq_any(&[("protocol-type", "rtu"),
+                                                            ("protocol-type", "ascii")]))]
     pub serial_parity: Option<ParityType>,
     /// the stop bits of the serial port
-    #[clap(arg_enum, short('o'), long, required_if_eq("protocol-type", "rtu"))]
+    #[clap(arg_enum, short('o'), long, required_if_eq_any(&[("protocol-type", "rtu"),
+                                                            ("protocol-type", "ascii")]))]
     pub serial_stop_bits: Option<StopBitsType>,
     /// the data bits of the serial port
-    #[clap(arg_enum, short('d'), long, required_if_eq("protocol-type", "rtu"))]
+    #[clap(arg_enum, short('d'), long, required_if_eq_any(&[("protocol-type", "rtu"),
+                                                            ("protocol-type", "ascii")]))]
     pub serial_data_bits: Option<DataBitsType>,
     /// the endianness of the Modbus communication
     #[clap(arg_enum, short('e'), long, required_unless_present("config-file"))]
@@ -75,7 +84,9 @@ pub struct Opts {
     #[clap(short('n'), long, required_if_eq_any(&[("function-code", "write-single-register"),
                                                   ("function-code", "write-multiple-registers"),
                                                   ("function-code", "write-single-coil"),
-                                                  ("function-code", "write-multiple-coils")]))]
+                                                  ("function-code", "write-multiple-coils"),
+                                                  ("function-code", "mask-write-register"),
+                                                  ("function-code", "write-file-record")]))]
     pub new_values: Option<Vec<String>>,
     /// the times to repeat the request in one-shot mode
     #[clap(short('g'), long, required_unless_present("config-file"))]
@@ -87,6 +98,34 @@ pub struct Opts {
     #[clap(arg_enum, short('j'), long, required_if_eq_any(&[("function-code", "write-single-register"),
                                                             ("function-code", "write-multiple-registers")]))]
     pub data_type: Option<DataType>,
+    /// the sub-function of a diagnostics request in one-shot mode
+    #[clap(
+        arg_enum,
+        short('u'),
+        long,
+        required_if_eq("function-code", "diagnostics")
+    )]
+    pub sub_function: Option<DiagnosticsSubFunction>,
+    /// the access type of a read device identification request in one-shot mode
+    #[clap(
+        arg_enum,
+        short('x'),
+        long,
+        required_if_eq("function-code", "read-device-identification")
+    )]
+    pub read_device_id_code: Option<ReadDeviceIdCode>,
+    /// the file number of a file record request in one-shot mode
+    #[clap(short('w'), long, required_if_eq_any(&[("function-code", "read-file-record"),
+                                                  ("function-code", "write-file-record")]))]
+    pub file_number: Option<u16>,
+    /// the function code of a custom request in one-shot mode, sending the new values as hex
+    #[clap(
+        short('z'),
+        long,
+        value_parser = parse_int::parse::<u8>,
+        required_if_eq("function-code", "custom")
+    )]
+    pub custom_function_code: Option<u8>,
     /// the server id used in one-shot mode
     #[clap(short('k'), long, required_if_eq("device-type", "client"))]
     pub server_id: Option<u8>,
@@ -114,6 +153,24 @@ pub struct ModbusRequest {
     pub delay: Option<u64>,
     /// type of the data in the request
     pub data_type: Option<DataType>,
+    /// endianness overriding the common setting for this request
+    pub endianness: Option<EndiannessType>,
+    /// number of registers taken by each String, Bytes or BitField value to write
+    pub data_length: Option<u16>,
+    /// gain from the raw register value to the engineering value
+    pub scale: Option<f64>,
+    /// offset from the raw register value to the engineering value
+    pub offset: Option<f64>,
+    /// unit of the engineering value
+    pub unit: Option<String>,
+    /// the sub-function of a Diagnostics request
+    pub sub_function: Option<DiagnosticsSubFunction>,
+    /// the access type of a Read Device Identification request
+    pub read_device_id_code: Option<ReadDeviceIdCode>,
+    /// the file number of a Read/Write File Record request
+    pub file_number: Option<u16>,
+    /// the function code of a custom request, whose data is given in hex by the new values
+    pub custom_function_code: Option<u8>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -124,11 +181,11 @@ pub struct ModbusCommonConfig {
     pub device_type: DeviceType,
     /// the id of the client/server
     pub device_id: u8,
-    /// the socket address when using Modbus TCP
+    /// the socket address when using Modbus TCP or RTU over TCP
     pub ip_address: Option<SocketAddr>,
-    /// the serial port when using Modbus RTU
+    /// the serial port when using Modbus RTU or ASCII
     pub serial_port: Option<String>,
-    /// the baudrate when using Modbus RTU
+    /// the baudrate when using Modbus RTU or ASCII
     pub serial_baudrate: Option<u32>,
     /// the parity of the serial port
     pub serial_parity: Option<ParityType>,
@@ -138,6 +195,47 @@ pub struct ModbusCommonConfig {
     pub serial_data_bits: Option<DataBitsType>,
     /// the endianness of the Modbus communication
     pub endianness: EndiannessType,
+    /// transports a server is reachable over at once, in place of the one defined above
+    pub endpoints: Option<Vec<ModbusEndpointConfig>>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModbusEndpointConfig {
+    /// the modbus protocol type
+    pub protocol_type: ProtocolType,
+    /// the socket address when using Modbus TCP or RTU over TCP
+    pub ip_address: Option<SocketAddr>,
+    /// the serial port when using Modbus RTU or ASCII
+    pub serial_port: Option<String>,
+    /// the baudrate when using Modbus RTU or ASCII
+    pub serial_baudrate: Option<u32>,
+    /// the parity of the serial port
+    pub serial_parity: Option<ParityType>,
+    /// the stop bits of the serial port
+    pub serial_stop_bits: Option<StopBitsType>,
+    /// the data bits of the serial port
+    pub serial_data_bits: Option<DataBitsType>,
+}
+
+impl ModbusCommonConfig {
+    /// the transport defined by the common items
+    pub fn default_endpoint(&self) -> ModbusEndpointConfig {
+        ModbusEndpointConfig {
+            protocol_type: self.protocol_type,
+            ip_address: self.ip_address,
+            serial_port: self.serial_port.clone(),
+            serial_baudrate: self.serial_baudrate,
+            serial_parity: self.serial_parity,
+            serial_stop_bits: self.serial_stop_bits,
+            serial_data_bits: self.serial_data_bits,
+        }
+    }
+
+    pub fn endpoints(&self) -> Vec<ModbusEndpointConfig> {
+        self.endpoints
+            .clone()
+            .unwrap_or_else(|| vec![self.default_endpoint()])
+    }
 }
 
 pub const REPEAT_TIME_INDEFINITE: u16 = 0xFFFF;
@@ -161,9 +259,34 @@ pub struct ModbusClientConfig {
     /// requests send by the client
     pub requests: Vec<ModbusClientRequest>,
     /// the register database
+    #[allow(dead_code)]
     pub register_data: Option<ModbusRegisterDatabase>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReportServerIdConfig {
+    /// the device specific server ID bytes
+    pub server_id: Vec<u8>,
+    /// whether the run indicator status is ON
+    pub run_indicator: bool,
+}
+
+/// the response to a user-defined function code
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum CustomFunctionResponse {
+    /// echoes the request data
+    Echo,
+    /// responds with fixed bytes in hex, e.g. "01 02 AB CD"
+    Fixed { data: String },
+    /// responds with a byte count followed by the values of holding registers, read from the
+    /// start address and quantity configured, or otherwise from those in the request data
+    Registers {
+        start_address: Option<u16>,
+        quantity: Option<u16>,
+    },
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ModbusServerConfig {
     /// the register database
@@ -176,6 +299,34 @@ pub struct ModbusServerConfig {
     pub coil_data_file: Option<String>,
     /// the external program to run upon data updates
     pub external_program: Option<String>,
+    /// the long-running helper program exchanging JSON lines with the server
+    pub helper_program: Option<String>,
+    /// the status returned by Read Exception Status
+    pub exception_status: Option<u8>,
+    /// the objects returned by Read Device Identification
+    pub device_identification: Option<DeviceIdentification>,
+    /// the server ID and run indicator returned by Report Server ID
+    pub report_server_id: Option<ReportServerIdConfig>,
+    /// the FIFO queues read by Read FIFO Queue, keyed by the FIFO pointer address
+    pub fifo_queues: Option<HashMap<u16, Vec<u16>>>,
+    /// the files read and written by Read/Write File Record, keyed by file number
+    pub file_records: Option<HashMap<u16, FileRecordConfig>>,
+    /// the responses to user-defined function codes, keyed by function code
+    pub custom_function_codes: Option<HashMap<u8, CustomFunctionResponse>>,
+    /// the faults injected on demand, where the first one firing for a request takes effect
+    pub faults: Option<Vec<FaultConfig>>,
+    /// the latency of the responses, per server and per address range
+    pub latency: Option<LatencyConfig>,
+    /// the process model linking the registers and coils together, evaluated on a tick
+    pub model: Option<ProcessModelConfig>,
+    /// the script run upon reads, writes and on a timer
+    pub script: Option<ScriptConfig>,
+    /// the diagnostic counters and event log, maintained at runtime
+    #[serde(skip)]
+    pub diagnostics: SharedDiagnostics,
+    /// where to send the events to the helper program, once started
+    #[serde(skip)]
+    pub helper: Option<HelperHandle>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -186,13 +337,64 @@ pub struct ModbusDeviceConfig {
     pub client: Option<ModbusClientConfig>,
     /// configuration for Modbus server devices
     pub server: Option<ModbusServerConfig>,
+    /// configurations for multiple Modbus server devices, keyed by unit ID
+    pub servers: Option<HashMap<u8, ModbusServerConfig>>,
     /* internal application options */
     #[serde(default)]
     pub verbose_mode: bool,
 }
 
+impl ModbusDeviceConfig {
+    /// the server device(s) configured, either as "server" or as "servers"
+    pub fn servers(&self) -> impl Iterator<Item = &ModbusServerConfig> {
+        self.units().map(|(_, server)| server)
+    }
+
+    /// the server device(s) configured along with their unit IDs, where "server" has none
+    pub fn units(&self) -> impl Iterator<Item = (Option<u8>, &ModbusServerConfig)> {
+        self.server
+            .iter()
+            .map(|s| (None, s))
+            .chain(
+                self.servers
+                    .iter()
+                    .flat_map(|s| s.iter().map(|(id, s)| (Some(*id), s))),
+            )
+    }
+
+    pub fn servers_mut(&mut self) -> impl Iterator<Item = &mut ModbusServerConfig> {
+        self.units_mut()
+            .map(|(_, server)| server)
+    }
+
+    pub fn units_mut(&mut self) -> impl Iterator<Item = (Option<u8>, &mut ModbusServerConfig)> {
+        self.server
+            .iter_mut()
+            .map(|s| (None, s))
+            .chain(self.servers.iter_mut().flat_map(|s| {
+                s.iter_mut()
+                    .map(|(id, s)| (Some(*id), s))
+            }))
+    }
+
+    /// the server device with the given unit ID, or "server" if none
+    pub fn unit(&self, unit_id: Option<u8>) -> Option<&ModbusServerConfig> {
+        match unit_id {
+            Some(id) => self.servers.as_ref()?.get(&id),
+            None => self.server.as_ref(),
+        }
+    }
+
+    pub fn unit_mut(&mut self, unit_id: Option<u8>) -> Option<&mut ModbusServerConfig> {
+        match unit_id {
+            Some(id) => self.servers.as_mut()?.get_mut(&id),
+            None => self.server.as_mut(),
+        }
+    }
+}
+
 fn parse_config_str(config_str: &str) -> anyhow::Result<ModbusDeviceConfig> {
-    serde_yaml::from_str(&config_str).with_context(|| format!("failed to parse the config string"))
+    serde_yaml::from_str(config_str).context("failed to parse the config string")
 }
 
 pub fn configure(opts: &mut Opts) -> anyhow::Result<ModbusDeviceConfig> {
@@ -215,8 +417,10 @@ pub fn configure(opts: &mut Opts) -> anyhow::Result<ModbusDeviceConfig> {
                     serial_parity: opts.serial_parity,
                     serial_port: opts.serial_port.take(),
                     endianness: opts.endianness.unwrap(),
+                    endpoints: None,
                 },
                 server: None,
+                servers: None,
                 client: Some(ModbusClientConfig {
                     requests: vec![ModbusClientRequest {
                         server_id: opts.server_id,
@@ -226,12 +430,21 @@ pub fn configure(opts: &mut Opts) -> anyhow::Result<ModbusDeviceConfig> {
                         request: Some(ModbusRequest {
                             description: "".to_string(),
                             function_code: opts.function_code.unwrap(),
-                            access_start_address: opts.start_address.unwrap(),
-                            access_quantity: opts.quantity.unwrap(),
+                            access_start_address: opts.start_address.unwrap_or(0),
+                            access_quantity: opts.quantity.unwrap_or(0),
                             new_values: opts.new_values.take(),
                             repeat_times: opts.repeat_times,
                             delay: opts.delay,
                             data_type: opts.data_type,
+                            endianness: None,
+                            data_length: None,
+                            scale: None,
+                            offset: None,
+                            unit: None,
+                            sub_function: opts.sub_function,
+                            read_device_id_code: opts.read_device_id_code,
+                            file_number: opts.file_number,
+                            custom_function_code: opts.custom_function_code,
                         }),
                     }],
                     register_data: None,
@@ -245,17 +458,17 @@ pub fn configure(opts: &mut Opts) -> anyhow::Result<ModbusDeviceConfig> {
     }
 }
 
-pub fn build_serial(config: &ModbusDeviceConfig) -> Option<SerialStream> {
-    let device = config.common.serial_port.as_ref()?;
-    let baudrate = config.common.serial_baudrate?;
+pub fn build_serial(endpoint: &ModbusEndpointConfig) -> Option<SerialStream> {
+    let device = endpoint.serial_port.as_ref()?;
+    let baudrate = endpoint.serial_baudrate?;
     let builder = tokio_serial::new(device, baudrate);
     let mut port = SerialStream::open(&builder).unwrap();
 
-    port.set_parity(config.common.serial_parity?.into())
+    port.set_parity(endpoint.serial_parity?.into())
         .ok();
-    port.set_stop_bits(config.common.serial_stop_bits?.into())
+    port.set_stop_bits(endpoint.serial_stop_bits?.into())
         .ok();
-    port.set_data_bits(config.common.serial_data_bits?.into())
+    port.set_data_bits(endpoint.serial_data_bits?.into())
         .ok();
     Some(port)
 }
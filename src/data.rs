@@ -1,7 +1,16 @@
-use crate::{types::*, util::*};
-use anyhow;
+use crate::{generator::*, program::*, types::*, util::*};
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt::Write as FmtWrite};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as FmtWrite,
+};
+use tokio::time::Duration;
+
+/* The bits of a register mapped to coils are numbered from the least significant bit of the
+ * register value, regardless of how the register is laid out on the wire.
+ */
+const REGISTER_BIT_LAYOUT: EndiannessType = EndiannessType::LittleEndian;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModbusRegisterData {
@@ -15,229 +24,391 @@ pub struct ModbusRegisterData {
     pub data_type: DataType,
     /// data value in a string
     pub data_value: String,
+    /// endianness overriding the common setting for this data item
+    pub endianness: Option<EndiannessType>,
+    /// number of registers taken by a String, Bytes or BitField data item
+    pub data_length: Option<u16>,
+    /// gain from the raw register value to the engineering value
+    pub scale: Option<f64>,
+    /// offset from the raw register value to the engineering value
+    pub offset: Option<f64>,
+    /// unit of the engineering value
+    pub unit: Option<String>,
+    /// exception returned upon any access to this data item
+    pub exception: Option<ModbusExceptionCode>,
+    /// generator of the data value changing over time
+    pub generator: Option<Generator>,
+    /// external program to run after the data value is changed
+    pub external_program: Option<String>,
 }
 
 impl ModbusRegisterData {
+    pub fn new(data_type: DataType, data_value: String) -> Self {
+        ModbusRegisterData {
+            data_description: "".to_string(),
+            data_model_type: DataModelType::HoldingOrInputRegister,
+            data_access_type: None,
+            data_type,
+            data_value,
+            endianness: None,
+            data_length: None,
+            scale: None,
+            offset: None,
+            unit: None,
+            exception: None,
+            generator: None,
+            external_program: None,
+        }
+    }
+
+    /// updates the data value from its generator, if any, at the given time since the server
+    /// started
+    pub fn generate(&mut self, elapsed: Duration) {
+        let integer = self.holds_integer();
+        let is_numeric = self.data_type.is_numeric();
+        let Some(generator) = self.generator.as_mut() else {
+            return;
+        };
+        /* only a step sequence may go through values which aren't numbers */
+        if !is_numeric && !matches!(generator.waveform, Waveform::Steps { .. }) {
+            return;
+        }
+        let Some(value) = generator.generate(elapsed, &self.data_value, integer) else {
+            return;
+        };
+        /* a number out of the range of the data type is clamped, and a value which can't be
+         * encoded otherwise leaves the previous one as is */
+        match parse_number(&value) {
+            Some(number) if is_numeric => self.set_number(number),
+            _ => self.replace_value(value),
+        };
+    }
+
+    /// checks that the data type holds every value of the step sequence of the generator, if any
+    pub fn check_generator(&self) -> anyhow::Result<()> {
+        let Some(Waveform::Steps { values, .. }) = self
+            .generator
+            .as_ref()
+            .map(|g| &g.waveform)
+        else {
+            return Ok(());
+        };
+        let mut data = self.clone();
+        for value in values {
+            if !data.replace_value(value.clone()) {
+                anyhow::bail!("step value {} is invalid for {:?}", value, self.data_type);
+            }
+        }
+        Ok(())
+    }
+
+    /// whether the data value holds an engineering value, i.e. raw * scale + offset
+    pub fn is_scaled(&self) -> bool {
+        self.data_type.is_numeric() && (self.scale.is_some() || self.offset.is_some())
+    }
+
+    /// whether the data value holds an integer, rather than a float or an engineering value
+    fn holds_integer(&self) -> bool {
+        !self.is_scaled() && !matches!(self.data_type, DataType::Float32 | DataType::Float64)
+    }
+
+    /// the data value as a number, if the data type is numeric
+    pub fn number(&self) -> Option<f64> {
+        if !self.data_type.is_numeric() {
+            return None;
+        }
+        parse_number(&self.data_value)
+    }
+
+    /// the range of the data value of an integer data type, as an engineering value if scaled
+    fn value_range(&self) -> Option<(f64, f64)> {
+        let (min, max) = self.data_type.integer_range()?;
+        if !self.is_scaled() {
+            return Some((min, max));
+        }
+        let to_value = |raw: f64| raw * self.scale.unwrap_or(1.0) + self.offset.unwrap_or(0.0);
+        let (min, max) = (to_value(min), to_value(max));
+        Some((min.min(max), min.max(max)))
+    }
+
+    /// sets the data value from a number, clamped to the range of the data type and rounded to
+    /// an integer unless it holds a float or an engineering value, telling whether it fits
+    pub fn set_number(&mut self, value: f64) -> bool {
+        let value = match self.value_range() {
+            Some(_) if value.is_nan() => return false,
+            Some((min, max)) => value.clamp(min, max),
+            None => value,
+        };
+        let value = if self.holds_integer() {
+            (value.round() as i64).to_string()
+        } else {
+            format_float(value)
+        };
+        self.replace_value(value)
+    }
+
+    /// replaces the data value as long as the new one can be encoded, telling whether it can
+    fn replace_value(&mut self, value: String) -> bool {
+        let old_value = std::mem::replace(&mut self.data_value, value);
+        if self.write_into_u16(&mut Vec::new(), EndiannessType::BigEndian) == 0 {
+            self.data_value = old_value;
+            return false;
+        }
+        true
+    }
+
+    /// the data value with its unit, if any
+    pub fn display_value(&self) -> String {
+        match &self.unit {
+            Some(unit) => format!("{} {}", self.data_value, unit),
+            None => self.data_value.clone(),
+        }
+    }
+
+    /// converts the data value into the raw value to be encoded into registers
+    fn raw_value(&self) -> Option<String> {
+        if !self.is_scaled() {
+            return Some(self.data_value.clone());
+        }
+        let scale = self.scale.unwrap_or(1.0);
+        if scale == 0.0 {
+            return None;
+        }
+        let value = self
+            .data_value
+            .trim()
+            .parse::<f64>()
+            .ok()?;
+        let raw = (value - self.offset.unwrap_or(0.0)) / scale;
+        match self.data_type {
+            DataType::Float32 | DataType::Float64 => Some(raw.to_string()),
+            _ => Some((raw.round() as i128).to_string()),
+        }
+    }
+
+    /// converts a raw value decoded from registers into the data value
+    fn scaled_value(&self, raw_value: String) -> String {
+        if !self.is_scaled() {
+            return raw_value;
+        }
+        match raw_value.parse::<f64>() {
+            Ok(raw) => format_float(raw * self.scale.unwrap_or(1.0) + self.offset.unwrap_or(0.0)),
+            Err(_) => raw_value,
+        }
+    }
+
+    /// the number of registers taken by the data item
+    pub fn register_count(&self) -> usize {
+        if let Some(count) = self.data_type.register_count() {
+            return count;
+        }
+        let byte_count = match self.data_type {
+            DataType::String => self.data_value.len(),
+            DataType::Bytes => parse_bytes(&self.data_value).map_or(0, |b| b.len()),
+            _ => 0,
+        };
+        self.data_length
+            .map_or(byte_count.div_ceil(2), |l| l as usize)
+            .max(1)
+    }
+
     pub fn is_function_code_supported(&self, function_code: FunctionCode) -> bool {
         let access_type = self
             .data_access_type
-            .or_else(|| Some(DataAccessType::ReadWrite))
-            .unwrap();
+            .unwrap_or(DataAccessType::ReadWrite);
         match (access_type, self.data_model_type) {
             (DataAccessType::ReadOnly, DataModelType::DiscreteInputs) => {
-                if function_code == FunctionCode::ReadDiscreteInputs {
-                    true
-                } else {
-                    false
-                }
+                function_code == FunctionCode::ReadDiscreteInputs
             }
             (DataAccessType::ReadOnly, DataModelType::Coils) => {
-                if function_code == FunctionCode::ReadCoils {
-                    true
-                } else {
-                    false
-                }
+                function_code == FunctionCode::ReadCoils
             }
             (DataAccessType::ReadOnly, DataModelType::DiscreteInputsOrCoils) => {
-                if function_code == FunctionCode::ReadCoils
+                function_code == FunctionCode::ReadCoils
                     || function_code == FunctionCode::ReadDiscreteInputs
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::ReadOnly, DataModelType::InputRegister) => {
-                if function_code == FunctionCode::ReadInputRegisters {
-                    true
-                } else {
-                    false
-                }
+                function_code == FunctionCode::ReadInputRegisters
             }
             (DataAccessType::ReadOnly, DataModelType::HoldingRegister) => {
-                if function_code == FunctionCode::ReadHoldingRegisters {
-                    true
-                } else {
-                    false
-                }
+                function_code == FunctionCode::ReadHoldingRegisters
             }
             (DataAccessType::ReadOnly, DataModelType::HoldingOrInputRegister) => {
-                if function_code == FunctionCode::ReadHoldingRegisters
+                function_code == FunctionCode::ReadHoldingRegisters
                     || function_code == FunctionCode::ReadInputRegisters
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::ReadOnly, DataModelType::AllType) => {
-                if function_code == FunctionCode::ReadHoldingRegisters
+                function_code == FunctionCode::ReadHoldingRegisters
                     || function_code == FunctionCode::ReadInputRegisters
                     || function_code == FunctionCode::ReadDiscreteInputs
                     || function_code == FunctionCode::ReadCoils
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::WriteOnly, DataModelType::DiscreteInputs)
             | (DataAccessType::WriteOnly, DataModelType::Coils)
             | (DataAccessType::WriteOnly, DataModelType::DiscreteInputsOrCoils) => {
-                if function_code == FunctionCode::WriteMultipleCoils
+                function_code == FunctionCode::WriteMultipleCoils
                     || function_code == FunctionCode::WriteSingleCoil
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::WriteOnly, DataModelType::InputRegister)
             | (DataAccessType::WriteOnly, DataModelType::HoldingRegister)
             | (DataAccessType::WriteOnly, DataModelType::HoldingOrInputRegister) => {
-                if function_code == FunctionCode::WriteMultipleRegisters
+                function_code == FunctionCode::WriteMultipleRegisters
                     || function_code == FunctionCode::WriteSingleRegister
-                {
-                    true
-                } else {
-                    false
-                }
+                    || function_code == FunctionCode::MaskWriteRegister
             }
             (DataAccessType::WriteOnly, DataModelType::AllType) => {
-                if function_code == FunctionCode::WriteMultipleRegisters
+                function_code == FunctionCode::WriteMultipleRegisters
                     || function_code == FunctionCode::WriteSingleRegister
+                    || function_code == FunctionCode::MaskWriteRegister
                     || function_code == FunctionCode::WriteMultipleCoils
                     || function_code == FunctionCode::WriteSingleCoil
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::ReadWrite, DataModelType::InputRegister) => {
-                if function_code == FunctionCode::WriteMultipleRegisters
+                function_code == FunctionCode::WriteMultipleRegisters
                     || function_code == FunctionCode::WriteSingleRegister
+                    || function_code == FunctionCode::MaskWriteRegister
                     || function_code == FunctionCode::ReadInputRegisters
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::ReadWrite, DataModelType::HoldingRegister) => {
-                if function_code == FunctionCode::WriteMultipleRegisters
+                function_code == FunctionCode::WriteMultipleRegisters
                     || function_code == FunctionCode::WriteSingleRegister
+                    || function_code == FunctionCode::MaskWriteRegister
                     || function_code == FunctionCode::ReadHoldingRegisters
                     || function_code == FunctionCode::ReadWriteMultipleRegisters
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::ReadWrite, DataModelType::HoldingOrInputRegister) => {
-                if function_code == FunctionCode::WriteMultipleRegisters
+                function_code == FunctionCode::WriteMultipleRegisters
                     || function_code == FunctionCode::WriteSingleRegister
+                    || function_code == FunctionCode::MaskWriteRegister
                     || function_code == FunctionCode::ReadInputRegisters
                     || function_code == FunctionCode::ReadHoldingRegisters
                     || function_code == FunctionCode::ReadWriteMultipleRegisters
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::ReadWrite, DataModelType::AllType) => true,
             _ => false,
         }
     }
 
+    /// encodes the data value into registers, with the endianness of the data item if specified,
+    /// otherwise with the given endianness
     pub fn write_into_u16(&self, registers: &mut Vec<u16>, e: EndiannessType) -> usize {
-        match &self.data_type {
-            DataType::Float32 => {
-                if let Ok(value) = self.data_value.parse::<f32>() {
-                    let tmp = write_f32_into_u16(value, e);
-                    registers.extend(tmp);
-                    return 2;
-                }
-            }
-            DataType::Float64 => {
-                if let Ok(value) = self.data_value.parse::<f64>() {
-                    let tmp = write_f64_into_u16(value, e);
-                    registers.extend(tmp);
-                    return 4;
-                }
-            }
-            DataType::Uint32 => {
-                if let Ok(value) = parse_int::parse::<u32>(&self.data_value) {
-                    let tmp = vec![(value & 0xFFFF) as u16, (value >> 16) as u16];
-                    registers.extend(tmp);
-                    return 2;
-                }
+        self.write_into_u16_with_layout(registers, self.endianness.unwrap_or(e))
+    }
+
+    /// the data value as encoded into registers, in a fixed layout to compare values with
+    fn encoded(&self) -> Vec<u16> {
+        let mut registers = Vec::new();
+        self.write_into_u16_with_layout(&mut registers, EndiannessType::BigEndian);
+        registers
+    }
+
+    /// decodes the data value from registers, with the endianness of the data item if specified,
+    /// otherwise with the given endianness
+    pub fn read_from_u16(
+        &mut self,
+        it: &mut std::iter::Peekable<std::slice::Iter<u16>>,
+        e: EndiannessType,
+    ) -> usize {
+        self.read_from_u16_with_layout(it, self.endianness.unwrap_or(e))
+    }
+
+    fn write_into_u16_with_layout(&self, registers: &mut Vec<u16>, e: EndiannessType) -> usize {
+        let Some(raw_value) = self.raw_value() else {
+            return 0;
+        };
+        let tmp = match &self.data_type {
+            DataType::Float32 => raw_value
+                .parse::<f32>()
+                .map(|value| write_f32_into_u16(value, e))
+                .ok(),
+            DataType::Float64 => raw_value
+                .parse::<f64>()
+                .map(|value| write_f64_into_u16(value, e))
+                .ok(),
+            DataType::Uint16 => parse_int::parse::<u16>(&raw_value)
+                .map(|value| reorder_u16(&[value], e))
+                .ok(),
+            DataType::Int16 => parse_int::parse::<i16>(&raw_value)
+                .map(|value| write_i16_into_u16(value, e))
+                .ok(),
+            DataType::Uint8Pair => {
+                parse_pair::<u8>(&raw_value).map(|value| write_u8_pair_into_u16(value, e))
             }
-            DataType::Uint16 => {
-                if let Ok(value) = parse_int::parse::<u16>(&self.data_value) {
-                    registers.push(value);
-                    return 1;
-                }
+            DataType::Int8Pair => parse_pair::<i8>(&raw_value)
+                .map(|value| write_u8_pair_into_u16((value.0 as u8, value.1 as u8), e)),
+            DataType::Uint32 => parse_int::parse::<u32>(&raw_value)
+                .map(|value| write_u32_into_u16(value, e))
+                .ok(),
+            DataType::Uint64 => parse_int::parse::<u64>(&raw_value)
+                .map(|value| write_u64_into_u16(value, e))
+                .ok(),
+            DataType::Int32 => parse_int::parse::<i32>(&raw_value)
+                .map(|value| write_i32_into_u16(value, e))
+                .ok(),
+            DataType::Int64 => parse_int::parse::<i64>(&raw_value)
+                .map(|value| write_i64_into_u16(value, e))
+                .ok(),
+            DataType::String => Some(raw_value.as_bytes())
+                .filter(|b| b.len() <= self.register_count() * 2)
+                .map(|b| write_bytes_into_u16(b, self.register_count(), e)),
+            DataType::Bytes => parse_bytes(&raw_value)
+                .filter(|b| b.len() <= self.register_count() * 2)
+                .map(|b| write_bytes_into_u16(&b, self.register_count(), e)),
+            DataType::BitField => parse_int::parse::<u64>(&raw_value)
+                .ok()
+                .filter(|value| match self.register_count() {
+                    count @ 1..=3 => value >> (16 * count) == 0,
+                    count => count == 4,
+                })
+                .map(|value| write_bits_into_u16(value, self.register_count(), e)),
+        };
+        match tmp {
+            Some(tmp) => {
+                registers.extend(&tmp);
+                tmp.len()
             }
-            _ => todo!(),
+            None => 0,
         }
-        return 0;
     }
 
-    pub fn read_from_u16(
+    fn read_from_u16_with_layout(
         &mut self,
         it: &mut std::iter::Peekable<std::slice::Iter<u16>>,
-        e: EndiannessType
+        e: EndiannessType,
     ) -> usize {
-        match &self.data_type {
-            DataType::Float32 => {
-                let mut tmp = [0_u16; 2];
-                for idx in 0..2 {
-                    if let Some(data) = it.next() {
-                        tmp[idx] = *data;
-                    } else {
-                        return 0;
-                    }
-                }
-                self.data_value = write_u16_into_f32(&tmp, e).to_string();
-                return 2;
-            }
-            DataType::Float64 => {
-                let mut tmp = [0_u16; 4];
-                for idx in 0..4 {
-                    if let Some(data) = it.next() {
-                        tmp[idx] = *data;
-                    } else {
-                        return 0;
-                    }
-                }
-                self.data_value = write_u16_into_f64(&tmp, e).to_string();
-                return 4;
-            }
-            DataType::Uint32 => {
-                let mut tmp = [0_u16; 2];
-                for idx in 0..2 {
-                    if let Some(data) = it.next() {
-                        tmp[idx] = *data;
-                    } else {
-                        return 0;
-                    }
-                }
-                self.data_value = (tmp[0] as u32 | ((tmp[1] as u32) << 16)).to_string();
-                return 2;
+        let count = self.register_count();
+        let tmp: Vec<u16> = it.take(count).copied().collect();
+        if tmp.len() != count {
+            return 0;
+        }
+        let raw_value = match &self.data_type {
+            DataType::Float32 => write_u16_into_f32(&tmp, e).to_string(),
+            DataType::Float64 => write_u16_into_f64(&tmp, e).to_string(),
+            DataType::Uint16 => reorder_u16(&tmp, e)[0].to_string(),
+            DataType::Int16 => write_u16_into_i16(&tmp, e).to_string(),
+            DataType::Uint8Pair => {
+                let (first, second) = write_u16_into_u8_pair(&tmp, e);
+                format!("{}, {}", first, second)
             }
-            DataType::Uint16 => {
-                if let Some(data) = it.next() {
-                    self.data_value = data.to_string();
-                    return 1;
-                } else {
+            DataType::Int8Pair => {
+                let (first, second) = write_u16_into_u8_pair(&tmp, e);
+                format!("{}, {}", first as i8, second as i8)
+            }
+            DataType::Uint32 => write_u16_into_u32(&tmp, e).to_string(),
+            DataType::Uint64 => write_u16_into_u64(&tmp, e).to_string(),
+            DataType::Int32 => write_u16_into_i32(&tmp, e).to_string(),
+            DataType::Int64 => write_u16_into_i64(&tmp, e).to_string(),
+            DataType::String => write_u16_into_string(&tmp, e),
+            DataType::Bytes => format_bytes(&write_u16_into_bytes(&tmp, e)),
+            DataType::BitField => {
+                if count > 4 {
                     return 0;
                 }
+                format!("{:#0w$b}", write_u16_into_bits(&tmp, e), w = 16 * count + 2)
             }
-            _ => todo!(),
-        }
+        };
+        self.data_value = self.scaled_value(raw_value);
+        count
     }
 }
 
@@ -247,6 +418,125 @@ pub struct ModbusRegisterDatabase {
 }
 
 impl ModbusRegisterDatabase {
+    /// updates the data values from their generators at the given time since the server started
+    pub fn generate(&mut self, elapsed: Duration) {
+        self.db
+            .values_mut()
+            .for_each(|data| data.generate(elapsed));
+    }
+
+    /// checks the generators of the data items against their data types
+    pub fn check_generators(&self) -> anyhow::Result<()> {
+        for (addr, data) in &self.db {
+            data.check_generator()
+                .with_context(|| format!("invalid generator of register @ {}", addr))?;
+        }
+        Ok(())
+    }
+
+    /// fixes the register span of the strings and bytes with no data length to that of their
+    /// configured values, or of the longest steps of their generators, so that writes of other lengths leave the neighbouring registers be
+    pub fn fix_data_lengths(&mut self) {
+        for data in self.db.values_mut() {
+            if !matches!(data.data_type, DataType::String | DataType::Bytes)
+                || data.data_length.is_some()
+            {
+                continue;
+            }
+            /* a step sequence may go through longer values */
+            let mut count = data.register_count();
+            if let Some(Waveform::Steps { values, .. }) = data
+                .generator
+                .as_ref()
+                .map(|g| &g.waveform)
+            {
+                let mut step = data.clone();
+                for value in values {
+                    step.data_value = value.clone();
+                    count = count.max(step.register_count());
+                }
+            }
+            data.data_length = Some(count as u16);
+        }
+    }
+
+    /// whether any data value changes over time, hence upon a read as well
+    pub fn has_generators(&self) -> bool {
+        self.db
+            .values()
+            .any(|data| data.generator.is_some())
+    }
+
+    /// the values of the data items starting in the given address range, keyed by address
+    pub fn values_in(&self, first: u16, last: u16) -> BTreeMap<u16, String> {
+        self.db
+            .iter()
+            .filter(|(addr, _)| (first..=last).contains(*addr))
+            .map(|(addr, data)| (*addr, data.data_value.clone()))
+            .collect()
+    }
+
+    /// sets the data value of the data item at the given address, as long as it can be encoded
+    pub fn set_data_value(
+        &mut self,
+        register_addr: u16,
+        value: String,
+    ) -> anyhow::Result<(), ModbusExceptionCode> {
+        let data = self
+            .db
+            .get_mut(&register_addr)
+            .ok_or(ModbusExceptionCode::IllegalDataAddress)?;
+        if !data.replace_value(value) {
+            return Err(ModbusExceptionCode::IllegalDataValue);
+        }
+        Ok(())
+    }
+
+    /// the values of the data items having their own external program, keyed by address
+    pub fn watched_values(&self) -> HashMap<u16, (Vec<u16>, String)> {
+        self.db
+            .iter()
+            .filter(|(_, data)| data.external_program.is_some())
+            .map(|(addr, data)| (*addr, (data.encoded(), data.data_value.clone())))
+            .collect()
+    }
+
+    /// the changes from the watched values taken before, as encoded into registers, so that
+    /// the same value written in another way, e.g. "0x07" and "7", is no change
+    pub fn changes(&self, before: HashMap<u16, (Vec<u16>, String)>) -> Vec<ValueChange> {
+        before
+            .into_iter()
+            .filter_map(|(addr, (old_registers, old_value))| {
+                let data = self.db.get(&addr)?;
+                if data.encoded() == old_registers {
+                    return None;
+                }
+                Some(ValueChange {
+                    program: data.external_program.clone()?,
+                    address: addr,
+                    old_value,
+                    new_value: data.data_value.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// the values of the numeric data items, keyed by address
+    pub fn numbers(&self) -> impl Iterator<Item = (u16, f64)> + '_ {
+        self.db
+            .iter()
+            .filter_map(|(addr, data)| Some((*addr, data.number()?)))
+    }
+
+    /// sets the value of the numeric data item at the given address, telling whether there is
+    /// one the value fits in
+    pub fn set_number(&mut self, register_addr: u16, value: f64) -> bool {
+        match self.db.get_mut(&register_addr) {
+            Some(data) if data.data_type.is_numeric() => data.set_number(value),
+            _ => false,
+        }
+    }
+
     pub fn update_u16_registers(
         &mut self,
         register_addr: u16,
@@ -258,6 +548,9 @@ impl ModbusRegisterDatabase {
         let mut total_updated = 0_usize;
         let mut addr = register_addr;
         while let Some(data) = self.db.get_mut(&addr) {
+            if let Some(e) = data.exception {
+                return Err(e);
+            }
             if data.is_function_code_supported(function_code) {
                 let registers_updated = data.read_from_u16(&mut value_it, e);
                 if registers_updated != 0 {
@@ -273,7 +566,50 @@ impl ModbusRegisterDatabase {
                 return Err(ModbusExceptionCode::IllegalFunction);
             }
         }
-        return Err(ModbusExceptionCode::IllegalDataAddress);
+        Err(ModbusExceptionCode::IllegalDataAddress)
+    }
+
+    /// applies the AND/OR masks to the register at the given address, which may be any register
+    /// of a multi-register data item, and returns the new register value
+    pub fn mask_write_u16_register(
+        &mut self,
+        register_addr: u16,
+        and_mask: u16,
+        or_mask: u16,
+        e: EndiannessType,
+    ) -> anyhow::Result<u16, ModbusExceptionCode> {
+        /* an item starting at the address takes precedence over an overlapping one */
+        let start_addr = if self.db.contains_key(&register_addr) {
+            register_addr
+        } else {
+            *self
+                .db
+                .iter()
+                .find(|(addr, data)| {
+                    **addr <= register_addr
+                        && (register_addr - **addr) < data.register_count() as u16
+                })
+                .ok_or(ModbusExceptionCode::IllegalDataAddress)?
+                .0
+        };
+        let data = self.db.get_mut(&start_addr).unwrap();
+        if let Some(e) = data.exception {
+            return Err(e);
+        }
+        if !data.is_function_code_supported(FunctionCode::MaskWriteRegister) {
+            return Err(ModbusExceptionCode::IllegalFunction);
+        }
+        let mut registers = Vec::<u16>::new();
+        if data.write_into_u16(&mut registers, e) == 0 {
+            return Err(ModbusExceptionCode::IllegalDataValue);
+        }
+        let register = &mut registers[(register_addr - start_addr) as usize];
+        *register = (*register & and_mask) | (or_mask & !and_mask);
+        let value = *register;
+        if data.read_from_u16(&mut registers.iter().peekable(), e) == 0 {
+            return Err(ModbusExceptionCode::IllegalDataValue);
+        }
+        Ok(value)
     }
 
     pub fn request_u16_registers(
@@ -288,10 +624,16 @@ impl ModbusRegisterDatabase {
         let mut addr = register_addr;
         let mut printout = String::new();
         while let Some(data) = self.db.get(&addr) {
+            if let Some(e) = data.exception {
+                return Err(e);
+            }
             if data.is_function_code_supported(function_code) {
                 let registers_written = data.write_into_u16(&mut registers, e);
+                if registers_written == 0 {
+                    return Err(ModbusExceptionCode::IllegalDataValue);
+                }
                 writeln!(&mut printout, "{}", data.data_description).unwrap();
-                writeln!(&mut printout, "{} ===>", data.data_value).unwrap();
+                writeln!(&mut printout, "{} ===>", data.display_value()).unwrap();
                 if count >= registers_written {
                     count -= registers_written;
                     addr += registers_written as u16;
@@ -306,7 +648,7 @@ impl ModbusRegisterDatabase {
                 return Err(ModbusExceptionCode::IllegalFunction);
             }
         }
-        return Err(ModbusExceptionCode::IllegalDataAddress);
+        Err(ModbusExceptionCode::IllegalDataAddress)
     }
 }
 
@@ -338,106 +680,64 @@ pub struct ModbusCoilData {
     pub data_access_type: Option<DataAccessType>,
     /// (boolean) data value, or map to a bit of a registers
     pub data_value: ModbusCoilDataValueType,
-    /// external program to run after a register is updated
+    /// external program to run after the data value is changed
     pub external_program: Option<String>,
+    /// exception returned upon any access to this data item
+    pub exception: Option<ModbusExceptionCode>,
 }
 
 impl ModbusCoilData {
     pub fn is_function_code_supported(&self, function_code: FunctionCode) -> bool {
         let access_type = self
             .data_access_type
-            .or_else(|| Some(DataAccessType::ReadWrite))
-            .unwrap();
+            .unwrap_or(DataAccessType::ReadWrite);
         match (access_type, self.data_model_type) {
             (DataAccessType::ReadOnly, DataModelType::DiscreteInputs) => {
-                if function_code == FunctionCode::ReadDiscreteInputs {
-                    true
-                } else {
-                    false
-                }
+                function_code == FunctionCode::ReadDiscreteInputs
             }
             (DataAccessType::ReadOnly, DataModelType::Coils) => {
-                if function_code == FunctionCode::ReadCoils {
-                    true
-                } else {
-                    false
-                }
+                function_code == FunctionCode::ReadCoils
             }
             (DataAccessType::ReadOnly, DataModelType::DiscreteInputsOrCoils) => {
-                if function_code == FunctionCode::ReadCoils
+                function_code == FunctionCode::ReadCoils
                     || function_code == FunctionCode::ReadDiscreteInputs
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::ReadOnly, DataModelType::AllType) => {
-                if function_code == FunctionCode::ReadDiscreteInputs
+                function_code == FunctionCode::ReadDiscreteInputs
                     || function_code == FunctionCode::ReadCoils
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::WriteOnly, DataModelType::DiscreteInputs)
             | (DataAccessType::WriteOnly, DataModelType::Coils)
             | (DataAccessType::WriteOnly, DataModelType::DiscreteInputsOrCoils) => {
-                if function_code == FunctionCode::WriteMultipleCoils
+                function_code == FunctionCode::WriteMultipleCoils
                     || function_code == FunctionCode::WriteSingleCoil
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::WriteOnly, DataModelType::AllType) => {
-                if function_code == FunctionCode::WriteMultipleCoils
+                function_code == FunctionCode::WriteMultipleCoils
                     || function_code == FunctionCode::WriteSingleCoil
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::ReadWrite, DataModelType::Coils) => {
-                if function_code == FunctionCode::WriteMultipleCoils
+                function_code == FunctionCode::WriteMultipleCoils
                     || function_code == FunctionCode::WriteSingleCoil
                     || function_code == FunctionCode::ReadCoils
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::ReadWrite, DataModelType::DiscreteInputs) => {
-                if function_code == FunctionCode::WriteMultipleCoils
+                function_code == FunctionCode::WriteMultipleCoils
                     || function_code == FunctionCode::WriteSingleCoil
                     || function_code == FunctionCode::ReadDiscreteInputs
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::ReadWrite, DataModelType::DiscreteInputsOrCoils) => {
-                if function_code == FunctionCode::WriteMultipleCoils
+                function_code == FunctionCode::WriteMultipleCoils
                     || function_code == FunctionCode::WriteSingleCoil
                     || function_code == FunctionCode::ReadCoils
                     || function_code == FunctionCode::ReadDiscreteInputs
-                {
-                    true
-                } else {
-                    false
-                }
             }
             (DataAccessType::ReadWrite, DataModelType::AllType) => true,
             _ => false,
         }
     }
 
-    pub fn update(&mut self, value: bool, rdb: &mut ModbusRegisterDatabase, e: EndiannessType) {
+    pub fn update(&mut self, value: bool, rdb: &mut ModbusRegisterDatabase) {
         let d = &mut self.data_value;
         match d {
             ModbusCoilDataValueType::Independent(_) => {
@@ -447,22 +747,26 @@ impl ModbusCoilData {
                 let register = rdb
                     .db
                     .get_mut(&c.register)
-                    .expect(&format!("missing register @ {}", c.register));
+                    .unwrap_or_else(|| panic!("missing register @ {}", c.register));
                 let mut current_values = Vec::<u16>::new();
-                let _ = register.write_into_u16(&mut current_values, e);
+                let _ =
+                    register.write_into_u16_with_layout(&mut current_values, REGISTER_BIT_LAYOUT);
                 let register_idx = (c.bit / 16) as usize;
                 let bit_idx = (c.bit % 16) as usize;
-                if value == true {
-                    current_values[register_idx] = current_values[register_idx] | (1 << bit_idx);
+                if value {
+                    current_values[register_idx] |= 1 << bit_idx;
                 } else {
-                    current_values[register_idx] = current_values[register_idx] & (!(1 << bit_idx));
+                    current_values[register_idx] &= !(1 << bit_idx);
                 }
-                register.read_from_u16(&mut current_values.iter().peekable(), e);
+                register.read_from_u16_with_layout(
+                    &mut current_values.iter().peekable(),
+                    REGISTER_BIT_LAYOUT,
+                );
             }
         }
     }
 
-    pub fn read(&self, rdb: &ModbusRegisterDatabase, e: EndiannessType) -> bool {
+    pub fn read(&self, rdb: &ModbusRegisterDatabase) -> bool {
         let d = &self.data_value;
         match d {
             ModbusCoilDataValueType::Independent(IndependentCoil { value }) => *value,
@@ -470,9 +774,10 @@ impl ModbusCoilData {
                 let register = rdb
                     .db
                     .get(&c.register)
-                    .expect(&format!("missing register @ {}", c.register));
+                    .unwrap_or_else(|| panic!("missing register @ {}", c.register));
                 let mut current_values = Vec::<u16>::new();
-                let _ = register.write_into_u16(&mut current_values, e);
+                let _ =
+                    register.write_into_u16_with_layout(&mut current_values, REGISTER_BIT_LAYOUT);
                 let register_idx = (c.bit / 16) as usize;
                 let bit_idx = (c.bit % 16) as usize;
                 current_values[register_idx] & (1 << bit_idx) != 0
@@ -487,21 +792,82 @@ pub struct ModbusCoilDatabase {
 }
 
 impl ModbusCoilDatabase {
+    /// the values of the coils, keyed by address
+    pub fn values<'a>(
+        &'a self,
+        rdb: &'a ModbusRegisterDatabase,
+    ) -> impl Iterator<Item = (u16, bool)> + 'a {
+        self.db
+            .iter()
+            .map(|(addr, data)| (*addr, data.read(rdb)))
+    }
+
+    /// the values of the coils having their own external program, keyed by address
+    pub fn watched_values(&self, rdb: &ModbusRegisterDatabase) -> HashMap<u16, bool> {
+        self.db
+            .iter()
+            .filter(|(_, data)| data.external_program.is_some())
+            .map(|(addr, data)| (*addr, data.read(rdb)))
+            .collect()
+    }
+
+    /// the changes from the watched values taken before
+    pub fn changes(
+        &self,
+        before: HashMap<u16, bool>,
+        rdb: &ModbusRegisterDatabase,
+    ) -> Vec<ValueChange> {
+        before
+            .into_iter()
+            .filter_map(|(addr, old_value)| {
+                let data = self.db.get(&addr)?;
+                let new_value = data.read(rdb);
+                if new_value == old_value {
+                    return None;
+                }
+                Some(ValueChange {
+                    program: data.external_program.clone()?,
+                    address: addr,
+                    old_value: old_value.to_string(),
+                    new_value: new_value.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    /// sets the value of the coil at the given address, if there is one
+    pub fn set_value(
+        &mut self,
+        coil_addr: u16,
+        value: bool,
+        rdb: &mut ModbusRegisterDatabase,
+    ) -> bool {
+        match self.db.get_mut(&coil_addr) {
+            Some(data) => {
+                data.update(value, rdb);
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn update_coils(
         &mut self,
         coil_addr: u16,
         values: Vec<bool>,
         function_code: FunctionCode,
         rdb: &mut ModbusRegisterDatabase,
-        e: EndiannessType,
     ) -> anyhow::Result<usize, ModbusExceptionCode> {
         let mut value_it = values.iter().peekable();
         let mut total_updated = 0_usize;
         let mut addr = coil_addr;
         while let Some(data) = self.db.get_mut(&addr) {
+            if let Some(e) = data.exception {
+                return Err(e);
+            }
             if data.is_function_code_supported(function_code) {
                 if let Some(new_data) = value_it.next() {
-                    data.update(*new_data, rdb, e);
+                    data.update(*new_data, rdb);
                     total_updated += 1;
                 } else {
                     return Err(ModbusExceptionCode::IllegalDataValue);
@@ -514,7 +880,7 @@ impl ModbusCoilDatabase {
             }
             addr += 1;
         }
-        return Err(ModbusExceptionCode::IllegalDataAddress);
+        Err(ModbusExceptionCode::IllegalDataAddress)
     }
 
     pub fn read_coils(
@@ -523,15 +889,17 @@ impl ModbusCoilDatabase {
         count: u16,
         function_code: FunctionCode,
         rdb: &ModbusRegisterDatabase,
-        e: EndiannessType,
     ) -> anyhow::Result<Vec<bool>, ModbusExceptionCode> {
         let mut coils = Vec::<bool>::new();
         let mut count = count as usize;
         let mut addr = coil_addr;
         //let mut printout = String::new();
         while let Some(data) = self.db.get(&addr) {
+            if let Some(e) = data.exception {
+                return Err(e);
+            }
             if data.is_function_code_supported(function_code) {
-                coils.push(data.read(rdb, e));
+                coils.push(data.read(rdb));
                 count -= 1;
                 if count == 0 {
                     return Ok(coils);
@@ -541,6 +909,334 @@ impl ModbusCoilDatabase {
             }
             addr += 1;
         }
-        return Err(ModbusExceptionCode::IllegalDataAddress);
+        Err(ModbusExceptionCode::IllegalDataAddress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_endianness_override_then_encode_with_it() {
+        let mut data = ModbusRegisterData::new(DataType::Uint32, "0xAABBCCDD".to_string());
+        let mut registers = Vec::<u16>::new();
+        data.write_into_u16(&mut registers, EndiannessType::BigEndian);
+        assert_eq!(vec![0xAABB, 0xCCDD], registers);
+
+        data.endianness = Some(EndiannessType::LittleEndian);
+        registers.clear();
+        data.write_into_u16(&mut registers, EndiannessType::BigEndian);
+        assert_eq!(vec![0xCCDD, 0xAABB], registers);
+    }
+
+    #[test]
+    fn given_endianness_override_then_decode_with_it() {
+        let mut data = ModbusRegisterData::new(DataType::Uint32, "0".to_string());
+        data.endianness = Some(EndiannessType::LittleEndian);
+        let registers = [0xCCDD, 0xAABB];
+        assert_eq!(
+            2,
+            data.read_from_u16(&mut registers.iter().peekable(), EndiannessType::BigEndian)
+        );
+        assert_eq!((0xAABBCCDD_u32).to_string(), data.data_value);
+    }
+
+    #[test]
+    fn given_string_then_round_trip_through_registers() {
+        let mut data = ModbusRegisterData::new(DataType::String, "YAMS-01".to_string());
+        data.data_length = Some(5);
+        let mut registers = Vec::<u16>::new();
+        assert_eq!(
+            5,
+            data.write_into_u16(&mut registers, EndiannessType::BigEndian)
+        );
+        assert_eq!(vec![0x5941, 0x4D53, 0x2D30, 0x3100, 0x0000], registers);
+
+        let registers = [0x4142, 0x4344, 0x0000, 0x0000, 0x0000];
+        assert_eq!(
+            5,
+            data.read_from_u16(&mut registers.iter().peekable(), EndiannessType::BigEndian)
+        );
+        assert_eq!("ABCD", data.data_value);
+    }
+
+    #[test]
+    fn given_too_long_string_then_fail_to_encode() {
+        let mut data = ModbusRegisterData::new(DataType::String, "YAMS-01".to_string());
+        data.data_length = Some(2);
+        assert_eq!(
+            0,
+            data.write_into_u16(&mut Vec::<u16>::new(), EndiannessType::BigEndian)
+        );
+    }
+
+    #[test]
+    fn given_fixed_data_lengths_then_keep_register_span() {
+        let mut rdb = ModbusRegisterDatabase { db: HashMap::new() };
+        rdb.db.insert(
+            40001,
+            ModbusRegisterData::new(DataType::String, "ABC".to_string()),
+        );
+        rdb.db.insert(
+            40003,
+            ModbusRegisterData::new(DataType::Bytes, "0x0102".to_string()),
+        );
+        rdb.fix_data_lengths();
+        assert_eq!(Some(2), rdb.db[&40001].data_length);
+        assert_eq!(Some(1), rdb.db[&40003].data_length);
+
+        assert_eq!(Ok(()), rdb.set_data_value(40001, "A".to_string()));
+        assert_eq!(2, rdb.db[&40001].register_count());
+        assert_eq!(
+            Err(ModbusExceptionCode::IllegalDataValue),
+            rdb.set_data_value(40001, "ABCDE".to_string())
+        );
+        assert_eq!(
+            Err(ModbusExceptionCode::IllegalDataValue),
+            rdb.set_data_value(40003, "0x010203".to_string())
+        );
+    }
+
+    #[test]
+    fn given_bit_field_then_round_trip_through_registers() {
+        let mut data = ModbusRegisterData::new(DataType::BitField, "0b1010".to_string());
+        let mut registers = Vec::<u16>::new();
+        assert_eq!(
+            1,
+            data.write_into_u16(&mut registers, EndiannessType::BigEndian)
+        );
+        assert_eq!(vec![0x000A], registers);
+
+        let registers = [0x8001];
+        assert_eq!(
+            1,
+            data.read_from_u16(&mut registers.iter().peekable(), EndiannessType::BigEndian)
+        );
+        assert_eq!("0b1000000000000001", data.data_value);
+    }
+
+    #[test]
+    fn given_scaled_value_then_round_trip_through_raw_registers() {
+        let mut data = ModbusRegisterData::new(DataType::Uint16, "23.4".to_string());
+        data.scale = Some(0.1);
+        data.offset = Some(-40.0);
+        data.unit = Some("°C".to_string());
+        let mut registers = Vec::<u16>::new();
+        assert_eq!(
+            1,
+            data.write_into_u16(&mut registers, EndiannessType::BigEndian)
+        );
+        assert_eq!(vec![634], registers);
+
+        let registers = [650];
+        assert_eq!(
+            1,
+            data.read_from_u16(&mut registers.iter().peekable(), EndiannessType::BigEndian)
+        );
+        assert_eq!("25 °C", data.display_value());
+    }
+
+    #[test]
+    fn given_generator_then_generate_value_of_data_type() {
+        let mut data = ModbusRegisterData::new(DataType::Uint16, "0".to_string());
+        data.generator = Some(Generator::from(Waveform::Ramp {
+            from: 0.0,
+            to: 10.0,
+            period_s: 4.0,
+        }));
+        data.generate(Duration::from_secs(1));
+        assert_eq!("3", data.data_value);
+
+        data.scale = Some(0.1);
+        data.generate(Duration::from_secs(1));
+        assert_eq!("2.5", data.data_value);
+        let mut registers = Vec::<u16>::new();
+        assert_eq!(
+            1,
+            data.write_into_u16(&mut registers, EndiannessType::BigEndian)
+        );
+        assert_eq!(vec![25], registers);
+
+        let mut data = ModbusRegisterData::new(DataType::String, "IDLE".to_string());
+        data.generator = Some(Generator::from(Waveform::Ramp {
+            from: 0.0,
+            to: 10.0,
+            period_s: 4.0,
+        }));
+        data.generate(Duration::from_secs(1));
+        assert_eq!("IDLE", data.data_value);
+    }
+
+    #[test]
+    fn given_generated_values_out_of_range_then_clamp_them() {
+        let mut data = ModbusRegisterData::new(DataType::Uint16, "0".to_string());
+        data.generator = Some(Generator::from(Waveform::Sine {
+            offset: 0.0,
+            amplitude: 10.0,
+            period_s: 4.0,
+        }));
+        data.generate(Duration::from_secs(3));
+        assert_eq!("0", data.data_value);
+
+        let mut data = ModbusRegisterData::new(DataType::Int16, "0".to_string());
+        data.generator = Some(Generator::from(Waveform::Ramp {
+            from: 0.0,
+            to: 40000.0,
+            period_s: 1.0,
+        }));
+        data.generate(Duration::from_secs(1));
+        assert_eq!("32767", data.data_value);
+    }
+
+    #[test]
+    fn given_step_values_then_check_them_against_data_type() {
+        let steps = |values: &[&str]| {
+            Some(Generator::from(Waveform::Steps {
+                values: values
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect(),
+                interval_s: 1.0,
+            }))
+        };
+        let mut data = ModbusRegisterData::new(DataType::Uint16, "0".to_string());
+        data.generator = steps(&["1", "0x10"]);
+        assert!(data.check_generator().is_ok());
+        data.generator = steps(&["1", "70000"]);
+        assert!(data.check_generator().is_err());
+        data.generator = steps(&["1", "IDLE"]);
+        assert!(data.check_generator().is_err());
+
+        let mut rdb = ModbusRegisterDatabase { db: HashMap::new() };
+        let mut state = ModbusRegisterData::new(DataType::String, "IDLE".to_string());
+        state.generator = steps(&["IDLE", "RUNNING"]);
+        rdb.db.insert(40001, state);
+        rdb.fix_data_lengths();
+        assert_eq!(Some(4), rdb.db[&40001].data_length);
+        assert!(rdb.check_generators().is_ok());
+    }
+
+    #[test]
+    fn given_external_programs_then_report_changed_values_only() {
+        let mut rdb = ModbusRegisterDatabase { db: HashMap::new() };
+        let mut setpoint = ModbusRegisterData::new(DataType::Uint16, "0x01".to_string());
+        setpoint.external_program = Some("./on.setpoint.sh".to_string());
+        rdb.db.insert(40001, setpoint);
+        rdb.db.insert(
+            40002,
+            ModbusRegisterData::new(DataType::Uint16, "2".to_string()),
+        );
+        let mut cdb = ModbusCoilDatabase { db: HashMap::new() };
+        cdb.db.insert(
+            1,
+            ModbusCoilData {
+                data_description: "Setpoint bit0".to_string(),
+                data_model_type: DataModelType::Coils,
+                data_access_type: None,
+                data_value: ModbusCoilDataValueType::RegisterBit(RegisterBitCoil {
+                    register: 40001,
+                    bit: 0,
+                }),
+                external_program: Some("./on.bit0.sh".to_string()),
+                exception: None,
+            },
+        );
+        let e = EndiannessType::BigEndian;
+
+        let (registers, coils) = (rdb.watched_values(), cdb.watched_values(&rdb));
+        rdb.update_u16_registers(40001, vec![1, 3], FunctionCode::WriteMultipleRegisters, e)
+            .unwrap();
+        assert_eq!(Vec::<ValueChange>::new(), rdb.changes(registers));
+        assert_eq!(Vec::<ValueChange>::new(), cdb.changes(coils, &rdb));
+
+        let (registers, coils) = (rdb.watched_values(), cdb.watched_values(&rdb));
+        cdb.update_coils(1, vec![false], FunctionCode::WriteSingleCoil, &mut rdb)
+            .unwrap();
+        assert_eq!(
+            vec![ValueChange {
+                program: "./on.setpoint.sh".to_string(),
+                address: 40001,
+                old_value: "1".to_string(),
+                new_value: "0".to_string(),
+            }],
+            rdb.changes(registers)
+        );
+        assert_eq!(
+            vec![ValueChange {
+                program: "./on.bit0.sh".to_string(),
+                address: 1,
+                old_value: "true".to_string(),
+                new_value: "false".to_string(),
+            }],
+            cdb.changes(coils, &rdb)
+        );
+    }
+
+    #[test]
+    fn given_masks_then_update_register_of_data_item() {
+        let mut rdb = ModbusRegisterDatabase { db: HashMap::new() };
+        rdb.db.insert(
+            40001,
+            ModbusRegisterData::new(DataType::Uint16, "0x0012".to_string()),
+        );
+        rdb.db.insert(
+            40002,
+            ModbusRegisterData::new(DataType::Uint32, "0xAABBCCDD".to_string()),
+        );
+        let mut read_only = ModbusRegisterData::new(DataType::Uint16, "0".to_string());
+        read_only.data_access_type = Some(DataAccessType::ReadOnly);
+        rdb.db.insert(40004, read_only);
+        let e = EndiannessType::BigEndian;
+
+        /* the example from the Modbus specification */
+        assert_eq!(
+            Ok(0x0017),
+            rdb.mask_write_u16_register(40001, 0x00F2, 0x0025, e)
+        );
+        assert_eq!("23", rdb.db[&40001].data_value);
+        assert_eq!(
+            Ok(0xCC00),
+            rdb.mask_write_u16_register(40003, 0xFF00, 0x0000, e)
+        );
+        assert_eq!((0xAABBCC00_u32).to_string(), rdb.db[&40002].data_value);
+        assert_eq!(
+            Err(ModbusExceptionCode::IllegalFunction),
+            rdb.mask_write_u16_register(40004, 0, 0, e)
+        );
+        assert_eq!(
+            Err(ModbusExceptionCode::IllegalDataAddress),
+            rdb.mask_write_u16_register(40005, 0, 0, e)
+        );
+    }
+
+    #[test]
+    fn given_exception_configured_then_fail_access_with_it() {
+        let mut rdb = ModbusRegisterDatabase { db: HashMap::new() };
+        rdb.db.insert(
+            40001,
+            ModbusRegisterData::new(DataType::Uint16, "1".to_string()),
+        );
+        let mut busy = ModbusRegisterData::new(DataType::Uint16, "2".to_string());
+        busy.exception = Some(ModbusExceptionCode::ServerDeviceBusy);
+        rdb.db.insert(40002, busy);
+        let e = EndiannessType::BigEndian;
+
+        assert_eq!(
+            Ok(vec![1]),
+            rdb.request_u16_registers(40001, 1, FunctionCode::ReadHoldingRegisters, e)
+        );
+        assert_eq!(
+            Err(ModbusExceptionCode::ServerDeviceBusy),
+            rdb.request_u16_registers(40001, 2, FunctionCode::ReadHoldingRegisters, e)
+        );
+        assert_eq!(
+            Err(ModbusExceptionCode::ServerDeviceBusy),
+            rdb.update_u16_registers(40002, vec![3], FunctionCode::WriteSingleRegister, e)
+        );
+        assert_eq!(
+            Err(ModbusExceptionCode::ServerDeviceBusy),
+            rdb.mask_write_u16_register(40002, 0, 0, e)
+        );
     }
 }
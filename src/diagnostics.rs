@@ -0,0 +1,345 @@
+/* Diagnostic counters and communication event log of a Modbus server,
+ * as defined for the function codes 0x07, 0x08, 0x0B and 0x0C.
+ */
+use crate::types::*;
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+/// "The log can hold up to 64 events"
+const MAX_EVENTS: usize = 64;
+const EVENT_RESTART: u8 = 0x00;
+const EVENT_ENTER_LISTEN_ONLY: u8 = 0x04;
+const RECEIVE_EVENT: u8 = 0x80;
+const RECEIVE_COMM_ERROR: u8 = 0x02;
+const RECEIVE_LISTEN_ONLY: u8 = 0x20;
+const RECEIVE_BROADCAST: u8 = 0x40;
+const SEND_EVENT: u8 = 0x40;
+const SEND_READ_EXCEPTION: u8 = 0x01;
+const SEND_ABORT_EXCEPTION: u8 = 0x02;
+const SEND_BUSY_EXCEPTION: u8 = 0x04;
+const SEND_NAK_EXCEPTION: u8 = 0x08;
+const SEND_LISTEN_ONLY: u8 = 0x20;
+/// the data of a restart request asking to clear the event log as well
+const RESTART_CLEAR_LOG: u16 = 0xFF00;
+
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    pub listen_only: bool,
+    pub diagnostic_register: u16,
+    pub bus_message_count: u16,
+    pub bus_comm_error_count: u16,
+    pub bus_exception_error_count: u16,
+    pub server_message_count: u16,
+    pub server_no_response_count: u16,
+    pub server_nak_count: u16,
+    pub server_busy_count: u16,
+    pub bus_char_overrun_count: u16,
+    pub comm_event_counter: u16,
+    /// the most recent event first
+    events: VecDeque<u8>,
+}
+
+/// the diagnostics of a server, counted by the requests served side by side as well
+#[derive(Debug, Default)]
+pub struct SharedDiagnostics(Mutex<Diagnostics>);
+
+impl SharedDiagnostics {
+    pub fn lock(&self) -> MutexGuard<'_, Diagnostics> {
+        /* no counter is left half-updated by a panic elsewhere, hence no poisoning to care about */
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Clone for SharedDiagnostics {
+    fn clone(&self) -> Self {
+        SharedDiagnostics(Mutex::new(self.lock().clone()))
+    }
+}
+
+impl Diagnostics {
+    fn log_event(&mut self, event: u8) {
+        self.events.push_front(event);
+        self.events.truncate(MAX_EVENTS);
+    }
+
+    fn clear_counters(&mut self) {
+        self.diagnostic_register = 0;
+        self.bus_message_count = 0;
+        self.bus_comm_error_count = 0;
+        self.bus_exception_error_count = 0;
+        self.server_message_count = 0;
+        self.server_no_response_count = 0;
+        self.server_nak_count = 0;
+        self.server_busy_count = 0;
+        self.bus_char_overrun_count = 0;
+    }
+
+    pub fn count_bus_message(&mut self) {
+        self.bus_message_count = self.bus_message_count.wrapping_add(1);
+    }
+
+    pub fn count_comm_errors(&mut self, errors: u16) {
+        self.bus_comm_error_count = self
+            .bus_comm_error_count
+            .wrapping_add(errors);
+        let listen_only = if self.listen_only {
+            RECEIVE_LISTEN_ONLY
+        } else {
+            0
+        };
+        for _ in 0..errors.min(MAX_EVENTS as u16) {
+            self.log_event(RECEIVE_EVENT | RECEIVE_COMM_ERROR | listen_only);
+        }
+    }
+
+    /// counts a message addressed to this server
+    pub fn count_received(&mut self, broadcast: bool) {
+        self.server_message_count = self
+            .server_message_count
+            .wrapping_add(1);
+        let mut event = RECEIVE_EVENT;
+        if broadcast {
+            event |= RECEIVE_BROADCAST;
+        }
+        if self.listen_only {
+            event |= RECEIVE_LISTEN_ONLY;
+        }
+        self.log_event(event);
+    }
+
+    pub fn count_no_response(&mut self) {
+        self.server_no_response_count = self
+            .server_no_response_count
+            .wrapping_add(1);
+    }
+
+    /// counts a response sent by this server for the given request function code,
+    /// along with the exception code if any
+    pub fn count_sent(&mut self, function_code: u8, exception: Option<u8>) {
+        let mut event = SEND_EVENT;
+        match exception {
+            Some(code) => {
+                self.bus_exception_error_count = self
+                    .bus_exception_error_count
+                    .wrapping_add(1);
+                event |= match code {
+                    0x01..=0x03 => SEND_READ_EXCEPTION,
+                    0x04 => SEND_ABORT_EXCEPTION,
+                    0x05 => SEND_BUSY_EXCEPTION,
+                    0x06 => {
+                        self.server_busy_count = self.server_busy_count.wrapping_add(1);
+                        SEND_BUSY_EXCEPTION
+                    }
+                    0x07 => {
+                        self.server_nak_count = self.server_nak_count.wrapping_add(1);
+                        SEND_NAK_EXCEPTION
+                    }
+                    _ => 0,
+                };
+            }
+            /* fetching the event counter or log doesn't count as an event */
+            None if function_code != FunctionCode::GetCommeventCounter as u8
+                && function_code != FunctionCode::GetcommEventLog as u8 =>
+            {
+                self.comm_event_counter = self.comm_event_counter.wrapping_add(1);
+            }
+            None => {}
+        }
+        if self.listen_only {
+            event |= SEND_LISTEN_ONLY;
+        }
+        self.log_event(event);
+    }
+
+    /// handles a Diagnostics (0x08) request, where None means no response is sent
+    pub fn diagnose(
+        &mut self,
+        sub_function: u16,
+        data: &[u8],
+    ) -> Option<Result<Vec<u8>, ModbusExceptionCode>> {
+        let value = match data {
+            [hi, lo] => Some(u16::from_be_bytes([*hi, *lo])),
+            _ => None,
+        };
+        let echo = || {
+            let mut rsp = sub_function.to_be_bytes().to_vec();
+            rsp.extend_from_slice(data);
+            rsp
+        };
+        let counter = |count: u16| {
+            let mut rsp = sub_function.to_be_bytes().to_vec();
+            rsp.extend_from_slice(&count.to_be_bytes());
+            rsp
+        };
+        let sub_function = match DiagnosticsSubFunction::try_from(sub_function) {
+            Ok(s) => s,
+            Err(_) => return Some(Err(ModbusExceptionCode::IllegalFunction)),
+        };
+        if sub_function != DiagnosticsSubFunction::ReturnQueryData
+            && sub_function != DiagnosticsSubFunction::RestartCommunications
+            && value != Some(0)
+        {
+            return Some(Err(ModbusExceptionCode::IllegalDataValue));
+        }
+        Some(Ok(match sub_function {
+            DiagnosticsSubFunction::ReturnQueryData => echo(),
+            DiagnosticsSubFunction::RestartCommunications => {
+                if value != Some(0) && value != Some(RESTART_CLEAR_LOG) {
+                    return Some(Err(ModbusExceptionCode::IllegalDataValue));
+                }
+                /* no response is sent when restarting from listen only mode */
+                let was_listen_only = self.listen_only;
+                if value == Some(RESTART_CLEAR_LOG) {
+                    self.events.clear();
+                }
+                self.clear_counters();
+                self.comm_event_counter = 0;
+                self.listen_only = false;
+                self.log_event(EVENT_RESTART);
+                if was_listen_only {
+                    return None;
+                }
+                echo()
+            }
+            DiagnosticsSubFunction::ReturnDiagnosticRegister => counter(self.diagnostic_register),
+            DiagnosticsSubFunction::ForceListenOnlyMode => {
+                self.listen_only = true;
+                self.log_event(EVENT_ENTER_LISTEN_ONLY);
+                return None;
+            }
+            DiagnosticsSubFunction::ClearCounters => {
+                self.clear_counters();
+                echo()
+            }
+            DiagnosticsSubFunction::ReturnBusMessageCount => counter(self.bus_message_count),
+            DiagnosticsSubFunction::ReturnBusCommunicationErrorCount => {
+                counter(self.bus_comm_error_count)
+            }
+            DiagnosticsSubFunction::ReturnBusExceptionErrorCount => {
+                counter(self.bus_exception_error_count)
+            }
+            DiagnosticsSubFunction::ReturnServerMessageCount => counter(self.server_message_count),
+            DiagnosticsSubFunction::ReturnServerNoResponseCount => {
+                counter(self.server_no_response_count)
+            }
+            DiagnosticsSubFunction::ReturnServerNakCount => counter(self.server_nak_count),
+            DiagnosticsSubFunction::ReturnServerBusyCount => counter(self.server_busy_count),
+            DiagnosticsSubFunction::ReturnBusCharacterOverrunCount => {
+                counter(self.bus_char_overrun_count)
+            }
+            DiagnosticsSubFunction::ClearOverrunCounter => {
+                self.bus_char_overrun_count = 0;
+                echo()
+            }
+        }))
+    }
+
+    /// the response data of Get Comm Event Counter (0x0B)
+    pub fn comm_event_counter_response(&self) -> Vec<u8> {
+        /* the status word would be 0xFFFF while a program command is still in progress */
+        let mut rsp = 0_u16.to_be_bytes().to_vec();
+        rsp.extend_from_slice(&self.comm_event_counter.to_be_bytes());
+        rsp
+    }
+
+    /// the response data of Get Comm Event Log (0x0C)
+    pub fn comm_event_log_response(&self) -> Vec<u8> {
+        let mut rsp = vec![(6 + self.events.len()) as u8];
+        rsp.extend_from_slice(&0_u16.to_be_bytes());
+        rsp.extend_from_slice(&self.comm_event_counter.to_be_bytes());
+        rsp.extend_from_slice(&self.bus_message_count.to_be_bytes());
+        rsp.extend(self.events.iter());
+        rsp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_listen_only_mode_then_restart_without_response() {
+        let mut d = Diagnostics::default();
+        d.count_bus_message();
+        assert_eq!(
+            None,
+            d.diagnose(0x0004, &[0x00, 0x00])
+                .map(|r| r.ok())
+        );
+        assert!(d.listen_only);
+        assert_eq!(
+            None,
+            d.diagnose(0x0001, &[0x00, 0x00])
+                .map(|r| r.ok())
+        );
+        assert!(!d.listen_only);
+        assert_eq!(0, d.bus_message_count);
+    }
+
+    #[test]
+    fn given_counters_then_return_them_by_sub_function() {
+        let mut d = Diagnostics::default();
+        d.count_bus_message();
+        d.count_bus_message();
+        d.count_comm_errors(1);
+        d.count_received(false);
+        d.count_sent(0x03, Some(0x02));
+        assert_eq!(
+            Some(vec![0x00, 0x0B, 0x00, 0x02]),
+            d.diagnose(0x000B, &[0, 0])
+                .unwrap()
+                .ok()
+        );
+        assert_eq!(
+            Some(vec![0x00, 0x0C, 0x00, 0x01]),
+            d.diagnose(0x000C, &[0, 0])
+                .unwrap()
+                .ok()
+        );
+        assert_eq!(
+            Some(vec![0x00, 0x0D, 0x00, 0x01]),
+            d.diagnose(0x000D, &[0, 0])
+                .unwrap()
+                .ok()
+        );
+        assert_eq!(
+            Some(vec![0x00, 0x0E, 0x00, 0x01]),
+            d.diagnose(0x000E, &[0, 0])
+                .unwrap()
+                .ok()
+        );
+        assert_eq!(
+            Some(vec![0x00, 0x0A, 0x00, 0x00]),
+            d.diagnose(0x000A, &[0, 0])
+                .unwrap()
+                .ok()
+        );
+        assert_eq!(
+            Some(vec![0x00, 0x0B, 0x00, 0x00]),
+            d.diagnose(0x000B, &[0, 0])
+                .unwrap()
+                .ok()
+        );
+    }
+
+    #[test]
+    fn given_completed_messages_then_log_events() {
+        let mut d = Diagnostics::default();
+        d.count_bus_message();
+        d.count_received(false);
+        d.count_sent(0x03, None);
+        d.count_received(true);
+        assert_eq!(
+            vec![0x00, 0x00, 0x00, 0x01],
+            d.comm_event_counter_response()
+        );
+        assert_eq!(
+            vec![0x09, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0xC0, 0x40, 0x80],
+            d.comm_event_log_response()
+        );
+    }
+}
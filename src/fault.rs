@@ -0,0 +1,180 @@
+/* Faults injected by a Modbus server on demand,
+ * to test how a client copes with a misbehaving device.
+ */
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+use tokio_modbus::prelude::Request;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum FaultAction {
+    /// delays the response by the given milliseconds
+    Delay { ms: u64 },
+    /// sends no response
+    Drop,
+    /// responds with the given exception, without handling the request
+    Exception { code: ModbusExceptionCode },
+    /// corrupts the CRC of an RTU frame, or the LRC of an ASCII frame
+    CorruptCrc,
+    /// sends only the first half of the frame
+    Truncate,
+    /// responds with a transaction ID other than the one of the request, on TCP
+    WrongTransactionId,
+}
+
+impl FaultAction {
+    /// whether the fault is injected when sending the response, rather than in place of it
+    pub fn is_on_the_wire(&self) -> bool {
+        !matches!(self, FaultAction::Drop | FaultAction::Exception { .. })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FaultConfig {
+    /// the function code of the requests to fault, or any function code if not specified
+    pub function_code: Option<u8>,
+    /// the first address of the requests to fault, where a request faults when any address
+    /// it accesses is in the range
+    pub start_address: Option<u16>,
+    /// the last address of the requests to fault
+    pub end_address: Option<u16>,
+    /// the probability of a matching request to fault, from 0.0 to 1.0
+    pub probability: Option<f64>,
+    /// faults every n-th matching request
+    pub every: Option<u32>,
+    /// the number of times to fault at most
+    pub times: Option<u32>,
+    /// what the fault does
+    pub action: FaultAction,
+    /* runtime counters */
+    #[serde(skip)]
+    matched: u32,
+    #[serde(skip)]
+    fired: u32,
+}
+
+impl FaultConfig {
+    fn matches(&self, function_code: u8, addresses: Option<(u16, u16)>) -> bool {
+        if self
+            .function_code
+            .is_some_and(|f| f != function_code)
+        {
+            return false;
+        }
+        if self.start_address.is_none() && self.end_address.is_none() {
+            return true;
+        }
+        let start = self.start_address.unwrap_or(u16::MIN);
+        let end = self
+            .end_address
+            .or(self.start_address)
+            .unwrap_or(u16::MAX);
+        addresses.is_some_and(|(first, last)| first <= end && last >= start)
+    }
+
+    /// whether the fault fires for a matching request, following its schedule
+    fn fires(&mut self) -> bool {
+        self.matched = self.matched.wrapping_add(1);
+        if self
+            .times
+            .is_some_and(|t| self.fired >= t)
+        {
+            return false;
+        }
+        let scheduled = self
+            .every
+            .is_none_or(|n| n != 0 && self.matched.is_multiple_of(n));
+        let lucky = self
+            .probability
+            .is_none_or(|p| rand::random::<f64>() < p);
+        if scheduled && lucky {
+            self.fired += 1;
+        }
+        scheduled && lucky
+    }
+}
+
+/// the first and the last address accessed by a request, if any
+pub fn request_addresses(req: &Request) -> Option<(u16, u16)> {
+    let span = |addr: u16, cnt: usize| (addr, addr.saturating_add((cnt.max(1) - 1) as u16));
+    match req {
+        Request::ReadCoils(addr, cnt)
+        | Request::ReadDiscreteInputs(addr, cnt)
+        | Request::ReadInputRegisters(addr, cnt)
+        | Request::ReadHoldingRegisters(addr, cnt) => Some(span(*addr, *cnt as usize)),
+        Request::WriteSingleCoil(addr, _) | Request::WriteSingleRegister(addr, _) => {
+            Some(span(*addr, 1))
+        }
+        Request::WriteMultipleCoils(addr, values) => Some(span(*addr, values.len())),
+        Request::WriteMultipleRegisters(addr, values) => Some(span(*addr, values.len())),
+        Request::ReadWriteMultipleRegisters(read_addr, cnt, write_addr, values) => {
+            let read = span(*read_addr, *cnt as usize);
+            let write = span(*write_addr, values.len());
+            Some((read.0.min(write.0), read.1.max(write.1)))
+        }
+        /* Mask Write Register and Read FIFO Queue */
+        Request::Custom(0x16 | 0x18, data) if data.len() >= 2 => {
+            Some(span(u16::from_be_bytes([data[0], data[1]]), 1))
+        }
+        _ => None,
+    }
+}
+
+/// the action of the first fault firing for the request, if any
+pub fn find_fault(
+    faults: &mut [FaultConfig],
+    function_code: u8,
+    req: &Request,
+) -> Option<FaultAction> {
+    let addresses = request_addresses(req);
+    faults
+        .iter_mut()
+        .filter(|f| f.matches(function_code, addresses))
+        .find_map(|f| f.fires().then_some(f.action))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_faults() -> Vec<FaultConfig> {
+        serde_yaml::from_str(
+            "
+            - { function_code: 0x03, start_address: 40010, end_address: 40019,
+                action: { type: Exception, code: ServerDeviceFailure } }
+            - { function_code: 0x06, every: 2, times: 2, action: { type: Drop } }
+            - { probability: 0.0, action: { type: Truncate } }
+            ",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn given_address_range_then_fault_overlapping_requests() {
+        let mut faults = build_faults();
+        let exception = Some(FaultAction::Exception {
+            code: ModbusExceptionCode::ServerDeviceFailure,
+        });
+        let read = |addr, cnt| Request::ReadHoldingRegisters(addr, cnt);
+        assert_eq!(exception, find_fault(&mut faults, 0x03, &read(40008, 3)));
+        assert_eq!(exception, find_fault(&mut faults, 0x03, &read(40019, 1)));
+        assert_eq!(None, find_fault(&mut faults, 0x03, &read(40001, 9)));
+        assert_eq!(
+            None,
+            find_fault(&mut faults, 0x04, &Request::ReadInputRegisters(40010, 1))
+        );
+    }
+
+    #[test]
+    fn given_schedule_then_fault_every_nth_request_up_to_times() {
+        let mut faults = build_faults();
+        let write = Request::WriteSingleRegister(40001, 1);
+        let fired: Vec<_> = (0..8)
+            .map(|_| find_fault(&mut faults, 0x06, &write).is_some())
+            .collect();
+        assert_eq!(
+            vec![false, true, false, true, false, false, false, false],
+            fired
+        );
+    }
+}
@@ -1,28 +1,169 @@
-use crate::config::*;
-use std::fs;
-
-pub fn write_data_to_files(server: &ModbusServerConfig) {
-    let r_str = serde_yaml::to_string(&server.register_data).unwrap();
-    let c_str = serde_yaml::to_string(&server.coil_data).unwrap();
-    fs::write(
-        server
-            .register_data_file
-            .as_ref()
-            .unwrap(),
-        r_str,
-    )
-    .unwrap();
-    fs::write(server.coil_data_file.as_ref().unwrap(), c_str).unwrap();
-}
+use crate::{config::*, data::*, util::*};
+use anyhow::{self, Context};
+use std::{collections::HashMap, fs};
+use tokio::process::Command;
 
-pub fn read_data_from_files(server: &mut ModbusServerConfig) {
+fn data_files(server: &ModbusServerConfig) -> anyhow::Result<(String, String)> {
     let rd_file = server
         .register_data_file
-        .as_ref()
-        .unwrap();
-    let cd_file = server.coil_data_file.as_ref().unwrap();
-    let r_data = fs::read_to_string(rd_file).expect("failed to read register data file");
-    let c_data = fs::read_to_string(cd_file).expect("failed to read coil data file");
-    server.register_data = serde_yaml::from_str(&r_data).unwrap();
-    server.coil_data = serde_yaml::from_str(&c_data).unwrap();
+        .clone()
+        .context("no register data file for the external program")?;
+    let cd_file = server
+        .coil_data_file
+        .clone()
+        .context("no coil data file for the external program")?;
+    Ok((rd_file, cd_file))
+}
+
+pub fn write_data_to_files(server: &ModbusServerConfig) -> anyhow::Result<()> {
+    let (rd_file, cd_file) = data_files(server)?;
+    fs::write(&rd_file, serde_yaml::to_string(&server.register_data)?)
+        .with_context(|| format!("failed to write {}", rd_file))?;
+    fs::write(&cd_file, serde_yaml::to_string(&server.coil_data)?)
+        .with_context(|| format!("failed to write {}", cd_file))?;
+    Ok(())
+}
+
+/// the data picked up from the files after a run of the external program
+pub type ProgramData = (ModbusRegisterDatabase, ModbusCoilDatabase);
+
+/// the data in the files, unless either of them is invalid
+fn read_data(rd_file: &str, cd_file: &str) -> anyhow::Result<ProgramData> {
+    let r_data = fs::read_to_string(rd_file).context("failed to read register data file")?;
+    let c_data = fs::read_to_string(cd_file).context("failed to read coil data file")?;
+    let register_data = serde_yaml::from_str(&r_data).context("invalid register data file")?;
+    let coil_data = serde_yaml::from_str(&c_data).context("invalid coil data file")?;
+    Ok((register_data, coil_data))
+}
+
+/// a run of the external program of a server, with the data shared through the files
+pub struct ExternalProgramRun {
+    program: String,
+    rd_file: String,
+    cd_file: String,
+    /* the data as shared, to tell what the program has changed */
+    register_data: ModbusRegisterDatabase,
+    coil_data: ModbusCoilDatabase,
+}
+
+impl ExternalProgramRun {
+    /// takes the data to share with the external program of the server, if any, leaving the
+    /// files to be written by the run
+    pub fn prepare(server: &ModbusServerConfig) -> Option<Self> {
+        let program = server.external_program.clone()?;
+        let (rd_file, cd_file) = match data_files(server) {
+            Ok(files) => files,
+            Err(e) => {
+                println!("failed in sharing the data with {}: {:#}", program, e);
+                return None;
+            }
+        };
+        Some(ExternalProgramRun {
+            program,
+            rd_file,
+            cd_file,
+            register_data: server.register_data.clone(),
+            coil_data: server.coil_data.clone(),
+        })
+    }
+
+    /// shares the data through the files, written on a blocking task so as not to hold up the
+    /// other requests
+    async fn share(&self) -> anyhow::Result<()> {
+        let files = [
+            (
+                self.rd_file.clone(),
+                serde_yaml::to_string(&self.register_data)?,
+            ),
+            (
+                self.cd_file.clone(),
+                serde_yaml::to_string(&self.coil_data)?,
+            ),
+        ];
+        tokio::task::spawn_blocking(move || {
+            files
+                .iter()
+                .try_for_each(|(file, data)| {
+                    fs::write(file, data).with_context(|| format!("failed to write {}", file))
+                })
+        })
+        .await?
+    }
+
+    /// the data the program has left in the files, read on a blocking task as well
+    async fn read_back(&self) -> anyhow::Result<ProgramData> {
+        let (rd_file, cd_file) = (self.rd_file.clone(), self.cd_file.clone());
+        tokio::task::spawn_blocking(move || read_data(&rd_file, &cd_file)).await?
+    }
+
+    /// shares the data and runs the program until it exits, giving the data to pick up from the
+    /// files, with no access to the data meanwhile, so that the other requests carry on
+    pub async fn run(&self, verbose_mode: bool) -> Option<ProgramData> {
+        if let Err(e) = self.share().await {
+            println!("failed in sharing the data with {}: {:#}", self.program, e);
+            return None;
+        }
+        vprintln(
+            &format!("running external program: {}", self.program),
+            verbose_mode,
+        );
+        match Command::new(&self.program)
+            .output()
+            .await
+        {
+            Ok(output) if !output.status.success() => {
+                println!(
+                    "external program {} exited with {}",
+                    self.program, output.status
+                )
+            }
+            Ok(_) => {}
+            Err(e) => {
+                println!("failed to execute {}: {}", self.program, e);
+                return None;
+            }
+        }
+        match self.read_back().await {
+            Ok(data) => Some(data),
+            Err(e) => {
+                println!(
+                    "failed in picking up the data from {}: {:#}",
+                    self.program, e
+                );
+                None
+            }
+        }
+    }
+
+    /// picks up the values the program has changed in the files, leaving the others, possibly
+    /// written by other requests in the meantime, as they are
+    pub fn pick_up(self, server: &mut ModbusServerConfig, (register_data, coil_data): ProgramData) {
+        let shared = self
+            .register_data
+            .values_in(u16::MIN, u16::MAX);
+        for (addr, value) in register_data.values_in(u16::MIN, u16::MAX) {
+            if shared.get(&addr) == Some(&value) {
+                continue;
+            }
+            if let Err(e) = server
+                .register_data
+                .set_data_value(addr, value)
+            {
+                println!("failed in picking up register @ {}: {:?}", addr, e);
+            }
+        }
+        let shared: HashMap<_, _> = self
+            .coil_data
+            .values(&self.register_data)
+            .collect();
+        for (addr, value) in coil_data.values(&register_data) {
+            if shared.get(&addr) != Some(&value)
+                && !server
+                    .coil_data
+                    .set_value(addr, value, &mut server.register_data)
+            {
+                println!("failed in picking up coil @ {}", addr);
+            }
+        }
+    }
 }
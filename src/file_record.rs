@@ -0,0 +1,372 @@
+/* Files of records of a Modbus server,
+ * as accessed by Read File Record (0x14) and Write File Record (0x15).
+ */
+use crate::types::*;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// "The reference type must be specified as 6"
+const REFERENCE_TYPE: u8 = 0x06;
+const MAX_RECORD_NUMBER: u16 = 0x270F;
+const SUB_REQUEST_HEADER_LEN: usize = 7;
+/// "a PDU is limited to 253 bytes", minus the function code and the data length
+const MAX_RESPONSE_DATA_LEN: usize = 253 - 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileRecordConfig {
+    /// the records of the file, each holding a 16-bit value
+    pub records: Option<Vec<u16>>,
+    /// the local file holding the records as big-endian 16-bit values, which takes
+    /// precedence over the records above
+    pub file: Option<String>,
+    /// whether the records have been written since last taken to be saved to the local file
+    #[serde(skip)]
+    unsaved: bool,
+}
+
+fn to_records(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks_exact(2)
+        .map(|w| u16::from_be_bytes([w[0], w[1]]))
+        .collect()
+}
+
+/// reads the records of the files backed by a local file, which are kept in memory from then on
+/// and saved back to the local file after each write
+pub fn load_file_records(files: &mut HashMap<u16, FileRecordConfig>) -> anyhow::Result<()> {
+    for file in files.values_mut() {
+        let Some(path) = &file.file else {
+            continue;
+        };
+        match fs::read(path) {
+            /* a file not created yet holds the records configured, if any */
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            result => {
+                let bytes = result
+                    .with_context(|| format!("failed in reading file records from {}", path))?;
+                file.records = Some(to_records(&bytes));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// the records of a local file to save
+pub struct RecordsSave {
+    path: String,
+    bytes: Vec<u8>,
+    revision: u64,
+}
+
+/// saves the records written to the local files with no lock held on the data, where the
+/// revisions keep the records of an earlier write from overwriting those of a later one
+#[derive(Default)]
+pub struct RecordsSaver {
+    /// the revision of the records last taken
+    revision: AtomicU64,
+    /// the revision of the records last saved to each local file
+    saved: tokio::sync::Mutex<HashMap<String, u64>>,
+}
+
+impl RecordsSaver {
+    /// takes the records written since last time, to be saved once the lock is released
+    pub fn take(&self, files: &mut HashMap<u16, FileRecordConfig>) -> Vec<RecordsSave> {
+        let mut saves = Vec::new();
+        for file in files.values_mut() {
+            if let (true, Some(path)) = (std::mem::take(&mut file.unsaved), &file.file) {
+                saves.push(RecordsSave {
+                    path: path.clone(),
+                    bytes: file
+                        .records
+                        .iter()
+                        .flatten()
+                        .flat_map(|r| r.to_be_bytes())
+                        .collect(),
+                    revision: self
+                        .revision
+                        .fetch_add(1, Ordering::Relaxed)
+                        + 1,
+                });
+            }
+        }
+        saves
+    }
+
+    /// saves the records taken, unless later ones have been saved already
+    pub async fn save(&self, saves: Vec<RecordsSave>) {
+        let mut saved = self.saved.lock().await;
+        for RecordsSave {
+            path,
+            bytes,
+            revision,
+        } in saves
+        {
+            if matches!(saved.get(&path), Some(&r) if r > revision) {
+                continue;
+            }
+            let file = path.clone();
+            match tokio::task::spawn_blocking(move || fs::write(file, bytes)).await {
+                Ok(Ok(())) => {
+                    saved.insert(path, revision);
+                }
+                _ => println!("failed in saving file records to {}", path),
+            }
+        }
+    }
+}
+
+/// a sub-request addressing a group of records within a file
+struct SubRequest<'a> {
+    file_number: u16,
+    record_number: u16,
+    record_length: u16,
+    /// the record data of a write sub-request
+    data: &'a [u8],
+}
+
+/// splits the request data into sub-requests, where each one of a write request
+/// carries its record data
+fn parse_sub_requests(
+    data: &[u8],
+    with_data: bool,
+) -> Result<Vec<SubRequest<'_>>, ModbusExceptionCode> {
+    let byte_count = *data
+        .first()
+        .ok_or(ModbusExceptionCode::IllegalDataValue)? as usize;
+    let mut rest = data
+        .get(1..)
+        .filter(|rest| rest.len() == byte_count && byte_count >= SUB_REQUEST_HEADER_LEN)
+        .ok_or(ModbusExceptionCode::IllegalDataValue)?;
+    let word_at = |b: &[u8], i: usize| u16::from_be_bytes([b[i], b[i + 1]]);
+    let mut sub_requests = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < SUB_REQUEST_HEADER_LEN || rest[0] != REFERENCE_TYPE {
+            return Err(ModbusExceptionCode::IllegalDataValue);
+        }
+        let record_length = word_at(rest, 5);
+        let data_len = if with_data {
+            record_length as usize * 2
+        } else {
+            0
+        };
+        let data = rest
+            .get(SUB_REQUEST_HEADER_LEN..SUB_REQUEST_HEADER_LEN + data_len)
+            .ok_or(ModbusExceptionCode::IllegalDataValue)?;
+        sub_requests.push(SubRequest {
+            file_number: word_at(rest, 1),
+            record_number: word_at(rest, 3),
+            record_length,
+            data,
+        });
+        rest = &rest[SUB_REQUEST_HEADER_LEN + data_len..];
+    }
+    Ok(sub_requests)
+}
+
+fn check_address(sub_request: &SubRequest) -> Result<(), ModbusExceptionCode> {
+    let last = sub_request.record_number as u32 + sub_request.record_length as u32;
+    if sub_request.file_number == 0
+        || sub_request.record_length == 0
+        || last > MAX_RECORD_NUMBER as u32 + 1
+    {
+        return Err(ModbusExceptionCode::IllegalDataAddress);
+    }
+    Ok(())
+}
+
+/// the response data of Read File Record (0x14)
+pub fn read_file_records(
+    files: &HashMap<u16, FileRecordConfig>,
+    data: &[u8],
+) -> Result<Vec<u8>, ModbusExceptionCode> {
+    let mut rsp = vec![0];
+    for sub_request in parse_sub_requests(data, false)? {
+        check_address(&sub_request)?;
+        let records = files
+            .get(&sub_request.file_number)
+            .ok_or(ModbusExceptionCode::IllegalDataAddress)?
+            .records
+            .as_deref()
+            .unwrap_or_default();
+        let start = sub_request.record_number as usize;
+        let records = records
+            .get(start..start + sub_request.record_length as usize)
+            .ok_or(ModbusExceptionCode::IllegalDataAddress)?;
+        /* the reference type and the records, checked to fit before the length byte is taken */
+        let sub_response_len = 1 + records.len() * 2;
+        if rsp.len() + sub_response_len > MAX_RESPONSE_DATA_LEN {
+            return Err(ModbusExceptionCode::IllegalDataValue);
+        }
+        rsp.push(sub_response_len as u8);
+        rsp.push(REFERENCE_TYPE);
+        records
+            .iter()
+            .for_each(|r| rsp.extend_from_slice(&r.to_be_bytes()));
+    }
+    rsp[0] = (rsp.len() - 1) as u8;
+    Ok(rsp)
+}
+
+/// writes the records of a Write File Record (0x15) request in memory, whose response data is
+/// the request data echoed, leaving the records to be saved to the local files by RecordsSaver
+pub fn write_file_records(
+    files: &mut HashMap<u16, FileRecordConfig>,
+    data: &[u8],
+) -> Result<Vec<u8>, ModbusExceptionCode> {
+    let sub_requests = parse_sub_requests(data, true)?;
+    /* check all sub-requests first so that a faulty one doesn't leave a partial write, as the
+     * records in memory can't fail to be written from then on */
+    for sub_request in &sub_requests {
+        check_address(sub_request)?;
+        if !files.contains_key(&sub_request.file_number) {
+            return Err(ModbusExceptionCode::IllegalDataAddress);
+        }
+    }
+    for sub_request in sub_requests {
+        let file = files
+            .get_mut(&sub_request.file_number)
+            .unwrap();
+        let records = file
+            .records
+            .get_or_insert_with(Vec::new);
+        let start = sub_request.record_number as usize;
+        let end = start + sub_request.record_length as usize;
+        /* writing past the end of a file extends it */
+        if records.len() < end {
+            records.resize(end, 0);
+        }
+        records[start..end]
+            .iter_mut()
+            .zip(sub_request.data.chunks_exact(2))
+            .for_each(|(r, w)| *r = u16::from_be_bytes([w[0], w[1]]));
+        file.unsaved = true;
+    }
+    Ok(data.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_files() -> HashMap<u16, FileRecordConfig> {
+        serde_yaml::from_str("{ 4: { records: [0x0DFE, 0x0020, 0x33CD, 0x0040] } }").unwrap()
+    }
+
+    #[test]
+    fn given_sub_requests_then_read_records() {
+        let files = build_files();
+        let req = [
+            0x0E, 0x06, 0x00, 0x04, 0x00, 0x01, 0x00, 0x02, 0x06, 0x00, 0x04, 0x00, 0x03, 0x00,
+            0x01,
+        ];
+        assert_eq!(
+            Ok(vec![
+                0x0A, 0x05, 0x06, 0x00, 0x20, 0x33, 0xCD, 0x03, 0x06, 0x00, 0x40
+            ]),
+            read_file_records(&files, &req)
+        );
+        let req = [0x07, 0x06, 0x00, 0x04, 0x00, 0x03, 0x00, 0x02];
+        assert_eq!(
+            Err(ModbusExceptionCode::IllegalDataAddress),
+            read_file_records(&files, &req)
+        );
+        let req = [0x07, 0x05, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01];
+        assert_eq!(
+            Err(ModbusExceptionCode::IllegalDataValue),
+            read_file_records(&files, &req)
+        );
+    }
+
+    #[test]
+    fn given_records_too_long_then_reject_them() {
+        let records = vec![0x1234_u16; 300];
+        let files =
+            serde_yaml::from_str(&format!("{{ 1: {{ records: {:?} }} }}", records)).unwrap();
+        /* 200 records wouldn't even fit in the length byte */
+        let req = [0x07, 0x06, 0x00, 0x01, 0x00, 0x00, 0x00, 0xC8];
+        assert_eq!(
+            Err(ModbusExceptionCode::IllegalDataValue),
+            read_file_records(&files, &req)
+        );
+        let req = [0x07, 0x06, 0x00, 0x01, 0x00, 0x00, 0x00, 0x7C];
+        assert_eq!(
+            Ok(251),
+            read_file_records(&files, &req).map(|rsp| rsp.len())
+        );
+        let req = [0x07, 0x06, 0x00, 0x01, 0x00, 0x00, 0x00, 0x7D];
+        assert_eq!(
+            Err(ModbusExceptionCode::IllegalDataValue),
+            read_file_records(&files, &req)
+        );
+    }
+
+    #[test]
+    fn given_sub_requests_then_write_records() {
+        let mut files = build_files();
+        let req = [
+            0x0B, 0x06, 0x00, 0x04, 0x00, 0x03, 0x00, 0x02, 0x06, 0xAF, 0x04, 0xBE,
+        ];
+        assert_eq!(Ok(req.to_vec()), write_file_records(&mut files, &req));
+        assert_eq!(
+            Some(vec![0x0DFE, 0x0020, 0x33CD, 0x06AF, 0x04BE]),
+            files[&4].records
+        );
+        let req = [0x09, 0x06, 0x00, 0x05, 0x00, 0x00, 0x00, 0x01, 0x12, 0x34];
+        assert_eq!(
+            Err(ModbusExceptionCode::IllegalDataAddress),
+            write_file_records(&mut files, &req)
+        );
+    }
+
+    #[test]
+    fn given_faulty_sub_request_then_write_nothing() {
+        let mut files = build_files();
+        /* the second sub-request addresses a file not configured */
+        let req = [
+            0x12, 0x06, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01, 0x12, 0x34, 0x06, 0x00, 0x05, 0x00,
+            0x00, 0x00, 0x01, 0x56, 0x78,
+        ];
+        assert_eq!(
+            Err(ModbusExceptionCode::IllegalDataAddress),
+            write_file_records(&mut files, &req)
+        );
+        assert_eq!(
+            Some(vec![0x0DFE, 0x0020, 0x33CD, 0x0040]),
+            files[&4].records
+        );
+    }
+
+    #[tokio::test]
+    async fn given_local_file_then_save_the_latest_records() {
+        let path = std::env::temp_dir().join("yams.file.records");
+        let _ = fs::remove_file(&path);
+        let config = format!(
+            "{{ 4: {{ records: [0x0001], file: \"{}\" }} }}",
+            path.display()
+        );
+        let mut files: HashMap<u16, FileRecordConfig> = serde_yaml::from_str(&config).unwrap();
+        load_file_records(&mut files).unwrap();
+        let saver = RecordsSaver::default();
+        let req = [0x09, 0x06, 0x00, 0x04, 0x00, 0x01, 0x00, 0x01, 0x12, 0x34];
+        assert!(write_file_records(&mut files, &req).is_ok());
+        let earlier = saver.take(&mut files);
+        assert_eq!(1, earlier.len());
+        assert!(saver.take(&mut files).is_empty());
+        let req = [0x09, 0x06, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01, 0xAB, 0xCD];
+        assert!(write_file_records(&mut files, &req).is_ok());
+        let later = saver.take(&mut files);
+        /* the records of the earlier write saved last don't overwrite the later ones */
+        saver.save(later).await;
+        saver.save(earlier).await;
+        assert_eq!(vec![0xAB, 0xCD, 0x12, 0x34], fs::read(&path).unwrap());
+        let mut files: HashMap<u16, FileRecordConfig> = serde_yaml::from_str(&config).unwrap();
+        load_file_records(&mut files).unwrap();
+        assert_eq!(Some(vec![0xABCD, 0x1234]), files[&4].records);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,520 @@
+/* Modbus ADU framing for the server side, and for the ASCII client.
+ *
+ * The tokio-modbus server skeletons hide the ADU header (hence the unit ID) from the service,
+ * so yams decodes the frames itself and hands the raw PDU over to tokio-modbus for parsing.
+ * tokio-modbus doesn't support Modbus ASCII at all, which is framed here for both directions.
+ */
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io::{Error, ErrorKind, Result};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// "The maximum size of a MODBUS RTU frame is 256 bytes."
+const MAX_RTU_FRAME_LEN: usize = 256;
+/// the smallest RTU frame: unit ID, function code and CRC
+const MIN_RTU_FRAME_LEN: usize = 4;
+const MBAP_HEADER_LEN: usize = 7;
+const MBAP_PROTOCOL_ID: u16 = 0x0000;
+const ASCII_START: u8 = b':';
+const ASCII_END: &[u8] = b"\r\n";
+/// start, unit ID, up to 253 bytes of PDU and LRC in hex, CR and LF
+const MAX_ASCII_FRAME_LEN: usize = 513;
+/// requests to this unit ID are handled by every server, which never responds
+pub const BROADCAST_UNIT_ID: u8 = 0x00;
+/// the unit ID a Modbus TCP server accepts when addressed directly rather than through a gateway
+pub const TCP_DIRECT_UNIT_ID: u8 = 0xFF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// MBAP header, as used by Modbus TCP
+    Tcp,
+    /// unit ID and CRC, as used by Modbus RTU
+    Rtu,
+    /// unit ID and LRC in hex between ':' and CRLF, as used by Modbus ASCII
+    Ascii,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AduHeader {
+    Tcp { transaction_id: u16, unit_id: u8 },
+    Rtu { unit_id: u8 },
+    Ascii { unit_id: u8 },
+}
+
+impl AduHeader {
+    pub fn unit_id(&self) -> u8 {
+        match self {
+            AduHeader::Tcp { unit_id, .. }
+            | AduHeader::Rtu { unit_id }
+            | AduHeader::Ascii { unit_id } => *unit_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Adu {
+    pub header: AduHeader,
+    pub pdu: Bytes,
+}
+
+/// decodes requests and encodes responses for a server, or the other way around for a client;
+/// ASCII frames are decoded and encoded the same way in both directions
+#[derive(Debug)]
+pub struct AduCodec {
+    framing: Framing,
+    /// whether the frames to decode are responses rather than requests
+    responses: bool,
+    /// frames dropped for a wrong CRC/LRC, since last taken
+    comm_errors: u16,
+    /// whether bytes are being skipped after a corrupted frame
+    resyncing: bool,
+}
+
+impl AduCodec {
+    pub fn new(framing: Framing) -> Self {
+        AduCodec {
+            framing,
+            responses: false,
+            comm_errors: 0,
+            resyncing: false,
+        }
+    }
+
+    pub fn client(framing: Framing) -> Self {
+        AduCodec {
+            responses: true,
+            ..AduCodec::new(framing)
+        }
+    }
+
+    /// returns the number of corrupted frames since last called
+    pub fn take_comm_errors(&mut self) -> u16 {
+        std::mem::take(&mut self.comm_errors)
+    }
+
+    fn count_comm_error(&mut self) {
+        if !self.resyncing {
+            self.comm_errors = self.comm_errors.saturating_add(1);
+        }
+    }
+}
+
+pub fn calc_crc(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF_u16;
+    for b in data {
+        crc ^= *b as u16;
+        for _ in 0..8 {
+            if crc & 0x0001 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+pub fn calc_lrc(data: &[u8]) -> u8 {
+    data.iter()
+        .fold(0_u8, |acc, b| acc.wrapping_add(*b))
+        .wrapping_neg()
+}
+
+/// corrupts the checksum of an encoded frame, which a TCP frame doesn't have
+pub fn corrupt_checksum(framing: Framing, buf: &mut BytesMut) -> bool {
+    match framing {
+        Framing::Tcp => false,
+        Framing::Rtu => match buf.last_mut() {
+            Some(b) => {
+                *b ^= 0xFF;
+                true
+            }
+            None => false,
+        },
+        /* the last hex digit of the LRC comes right before CRLF */
+        Framing::Ascii => match buf
+            .len()
+            .checked_sub(ASCII_END.len() + 1)
+        {
+            Some(i) => {
+                buf[i] = if buf[i] == b'0' { b'1' } else { b'0' };
+                true
+            }
+            None => false,
+        },
+    }
+}
+
+/// the PDU length of an RTU request, if it can be told from the function code
+/// and the bytes received so far
+fn rtu_request_pdu_len(buf: &[u8]) -> Option<usize> {
+    let byte_count_at = |idx: usize, fixed: usize| {
+        buf.get(idx)
+            .map(|n| fixed + *n as usize)
+    };
+    match buf.get(1)? {
+        0x01..=0x06 | 0x08 => Some(5),
+        0x07 | 0x0B | 0x0C | 0x11 => Some(1),
+        0x0F | 0x10 => byte_count_at(6, 6),
+        0x14 | 0x15 => byte_count_at(2, 2),
+        0x16 => Some(7),
+        0x17 => byte_count_at(10, 10),
+        0x18 => Some(3),
+        _ => None,
+    }
+}
+
+/// the PDU length of an RTU response, if it can be told from the function code
+/// and the bytes received so far
+fn rtu_response_pdu_len(buf: &[u8]) -> Option<usize> {
+    let byte_count_at = |idx: usize, fixed: usize| {
+        buf.get(idx)
+            .map(|n| fixed + *n as usize)
+    };
+    match buf.get(1)? {
+        0x01..=0x04 | 0x0C | 0x11 | 0x14 | 0x15 | 0x17 => byte_count_at(2, 2),
+        0x05 | 0x06 | 0x0B | 0x0F | 0x10 => Some(5),
+        0x07 => Some(2),
+        0x16 => Some(7),
+        0x18 => Some(3 + u16::from_be_bytes([*buf.get(2)?, *buf.get(3)?]) as usize),
+        0x80..=0xFF => Some(2),
+        /* the query data echoed by Diagnostics is of any length */
+        _ => None,
+    }
+}
+
+/// the length of the RTU frame at the beginning of the buffer, found by checking the CRC at
+/// every possible frame length; used for function codes without a known request layout
+fn scan_rtu_frame_len(buf: &[u8]) -> Option<usize> {
+    (MIN_RTU_FRAME_LEN..=buf.len().min(MAX_RTU_FRAME_LEN))
+        .find(|len| calc_crc(&buf[..len - 2]) == u16::from_le_bytes([buf[len - 2], buf[len - 1]]))
+}
+
+fn decode_rtu(codec: &mut AduCodec, buf: &mut BytesMut) -> Result<Option<Adu>> {
+    loop {
+        if buf.len() < MIN_RTU_FRAME_LEN {
+            return Ok(None);
+        }
+        let pdu_len = if codec.responses {
+            rtu_response_pdu_len(buf)
+        } else {
+            rtu_request_pdu_len(buf)
+        };
+        let frame_len = match pdu_len {
+            Some(pdu_len) => {
+                let frame_len = pdu_len + 3;
+                if buf.len() < frame_len {
+                    return Ok(None);
+                }
+                let crc = u16::from_le_bytes([buf[frame_len - 2], buf[frame_len - 1]]);
+                Some(frame_len).filter(|_| calc_crc(&buf[..frame_len - 2]) == crc)
+            }
+            None => {
+                let frame_len = scan_rtu_frame_len(buf);
+                if frame_len.is_none() && buf.len() < MAX_RTU_FRAME_LEN {
+                    return Ok(None);
+                }
+                frame_len
+            }
+        };
+        match frame_len {
+            Some(frame_len) => {
+                codec.resyncing = false;
+                let mut frame = buf.split_to(frame_len);
+                let unit_id = frame.get_u8();
+                frame.truncate(frame_len - 3);
+                return Ok(Some(Adu {
+                    header: AduHeader::Rtu { unit_id },
+                    pdu: frame.freeze(),
+                }));
+            }
+            /* skip a byte to resynchronize with the frames on the line */
+            None => {
+                codec.count_comm_error();
+                codec.resyncing = true;
+                buf.advance(1);
+            }
+        }
+    }
+}
+
+fn decode_ascii(codec: &mut AduCodec, buf: &mut BytesMut) -> Result<Option<Adu>> {
+    loop {
+        /* anything before the start of a frame is noise */
+        match buf
+            .iter()
+            .position(|b| *b == ASCII_START)
+        {
+            Some(start) => buf.advance(start),
+            None => {
+                buf.clear();
+                return Ok(None);
+            }
+        }
+        let end = match buf
+            .windows(ASCII_END.len())
+            .position(|w| w == ASCII_END)
+        {
+            Some(end) => end,
+            None => {
+                if buf.len() > MAX_ASCII_FRAME_LEN {
+                    buf.advance(1);
+                    continue;
+                }
+                return Ok(None);
+            }
+        };
+        let frame = buf.split_to(end + ASCII_END.len());
+        let hex = &frame[1..end];
+        let data = std::str::from_utf8(hex)
+            .ok()
+            .filter(|hex| hex.len() >= 6 && hex.len() % 2 == 0)
+            .and_then(|hex| {
+                (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+                    .collect::<Option<Vec<u8>>>()
+            });
+        match data {
+            Some(data) if calc_lrc(&data[..data.len() - 1]) == data[data.len() - 1] => {
+                return Ok(Some(Adu {
+                    header: AduHeader::Ascii { unit_id: data[0] },
+                    pdu: Bytes::copy_from_slice(&data[1..data.len() - 1]),
+                }));
+            }
+            /* a corrupted frame is dropped, as an RTU frame with a wrong CRC */
+            _ => codec.count_comm_error(),
+        }
+    }
+}
+
+fn decode_tcp(buf: &mut BytesMut) -> Result<Option<Adu>> {
+    if buf.len() < MBAP_HEADER_LEN {
+        return Ok(None);
+    }
+    let transaction_id = u16::from_be_bytes([buf[0], buf[1]]);
+    let protocol_id = u16::from_be_bytes([buf[2], buf[3]]);
+    let len = u16::from_be_bytes([buf[4], buf[5]]) as usize;
+    if protocol_id != MBAP_PROTOCOL_ID || len < 2 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid MBAP header {:02X?}", &buf[..MBAP_HEADER_LEN]),
+        ));
+    }
+    if buf.len() < MBAP_HEADER_LEN + len - 1 {
+        return Ok(None);
+    }
+    let unit_id = buf[6];
+    buf.advance(MBAP_HEADER_LEN);
+    let pdu = buf.split_to(len - 1).freeze();
+    Ok(Some(Adu {
+        header: AduHeader::Tcp {
+            transaction_id,
+            unit_id,
+        },
+        pdu,
+    }))
+}
+
+impl Decoder for AduCodec {
+    type Item = Adu;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Adu>> {
+        match self.framing {
+            Framing::Tcp => decode_tcp(buf),
+            Framing::Rtu => decode_rtu(self, buf),
+            Framing::Ascii => decode_ascii(self, buf),
+        }
+    }
+}
+
+impl Encoder<Adu> for AduCodec {
+    type Error = Error;
+
+    fn encode(&mut self, adu: Adu, buf: &mut BytesMut) -> Result<()> {
+        match adu.header {
+            AduHeader::Tcp {
+                transaction_id,
+                unit_id,
+            } => {
+                buf.reserve(MBAP_HEADER_LEN + adu.pdu.len());
+                buf.put_u16(transaction_id);
+                buf.put_u16(MBAP_PROTOCOL_ID);
+                buf.put_u16(adu.pdu.len() as u16 + 1);
+                buf.put_u8(unit_id);
+                buf.put_slice(&adu.pdu);
+            }
+            AduHeader::Rtu { unit_id } => {
+                let start = buf.len();
+                buf.reserve(adu.pdu.len() + 3);
+                buf.put_u8(unit_id);
+                buf.put_slice(&adu.pdu);
+                let crc = calc_crc(&buf[start..]);
+                buf.put_u16_le(crc);
+            }
+            AduHeader::Ascii { unit_id } => {
+                let mut data = Vec::with_capacity(adu.pdu.len() + 2);
+                data.push(unit_id);
+                data.extend_from_slice(&adu.pdu);
+                data.push(calc_lrc(&data));
+                buf.reserve(data.len() * 2 + 3);
+                buf.put_u8(ASCII_START);
+                for b in data {
+                    buf.put_slice(format!("{:02X}", b).as_bytes());
+                }
+                buf.put_slice(ASCII_END);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_rtu_frame_then_get_correct_crc() {
+        assert_eq!(0x63B6, calc_crc(&[0x01, 0x03, 0x08, 0x2B, 0x00, 0x02]));
+        assert_eq!(
+            0xF9FB,
+            calc_crc(&[0x01, 0x03, 0x04, 0x00, 0x20, 0x00, 0x00])
+        );
+    }
+
+    #[test]
+    fn given_rtu_request_then_decode_unit_id_and_pdu() {
+        let mut codec = AduCodec::new(Framing::Rtu);
+        let mut buf = BytesMut::from(&[0x11, 0x03, 0x00, 0x6B, 0x00, 0x03, 0x76, 0x87][..]);
+        let adu = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(AduHeader::Rtu { unit_id: 0x11 }, adu.header);
+        assert_eq!(&[0x03, 0x00, 0x6B, 0x00, 0x03][..], &adu.pdu[..]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn given_partial_rtu_request_then_wait_for_more() {
+        let mut codec = AduCodec::new(Framing::Rtu);
+        let mut buf = BytesMut::from(&[0x11, 0x03, 0x00, 0x6B, 0x00][..]);
+        assert_eq!(None, codec.decode(&mut buf).unwrap());
+        assert_eq!(5, buf.len());
+    }
+
+    #[test]
+    fn given_garbage_before_rtu_request_then_resynchronize() {
+        let mut codec = AduCodec::new(Framing::Rtu);
+        let mut buf = BytesMut::from(&[0xFF, 0x11, 0x03, 0x00, 0x6B, 0x00, 0x03, 0x76, 0x87][..]);
+        let adu = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(AduHeader::Rtu { unit_id: 0x11 }, adu.header);
+        assert_eq!(1, codec.take_comm_errors());
+        assert_eq!(0, codec.take_comm_errors());
+    }
+
+    #[test]
+    fn given_rtu_request_with_unknown_function_code_then_find_frame_by_crc() {
+        let mut frame = vec![0x01, 0x41, 0xDE, 0xAD];
+        let crc = calc_crc(&frame);
+        frame.extend(crc.to_le_bytes());
+        let mut codec = AduCodec::new(Framing::Rtu);
+        let mut buf = BytesMut::from(&frame[..]);
+        let adu = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(&[0x41, 0xDE, 0xAD][..], &adu.pdu[..]);
+    }
+
+    #[test]
+    fn given_rtu_response_then_encode_with_crc() {
+        let mut codec = AduCodec::new(Framing::Rtu);
+        let mut buf = BytesMut::new();
+        let adu = Adu {
+            header: AduHeader::Rtu { unit_id: 0x01 },
+            pdu: Bytes::from_static(&[0x03, 0x04, 0x00, 0x20, 0x00, 0x00]),
+        };
+        codec.encode(adu, &mut buf).unwrap();
+        assert_eq!(
+            &[0x01, 0x03, 0x04, 0x00, 0x20, 0x00, 0x00, 0xFB, 0xF9][..],
+            &buf[..]
+        );
+    }
+
+    #[test]
+    fn given_tcp_request_then_decode_and_encode_mbap_header() {
+        let mut codec = AduCodec::new(Framing::Tcp);
+        let mut buf = BytesMut::from(
+            &[
+                0x00, 0x2A, 0x00, 0x00, 0x00, 0x06, 0x05, 0x03, 0x00, 0x6B, 0x00, 0x03,
+            ][..],
+        );
+        let adu = codec.decode(&mut buf).unwrap().unwrap();
+        let header = AduHeader::Tcp {
+            transaction_id: 0x2A,
+            unit_id: 0x05,
+        };
+        assert_eq!(header, adu.header);
+        assert_eq!(&[0x03, 0x00, 0x6B, 0x00, 0x03][..], &adu.pdu[..]);
+
+        let mut buf = BytesMut::new();
+        let adu = Adu {
+            header,
+            pdu: Bytes::from_static(&[0x83, 0x02]),
+        };
+        codec.encode(adu, &mut buf).unwrap();
+        assert_eq!(
+            &[0x00, 0x2A, 0x00, 0x00, 0x00, 0x03, 0x05, 0x83, 0x02][..],
+            &buf[..]
+        );
+    }
+
+    #[test]
+    fn given_ascii_request_then_decode_unit_id_and_pdu() {
+        let mut codec = AduCodec::new(Framing::Ascii);
+        let mut buf = BytesMut::from(&b"\0:1103006B00037E\r\n"[..]);
+        let adu = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(AduHeader::Ascii { unit_id: 0x11 }, adu.header);
+        assert_eq!(&[0x03, 0x00, 0x6B, 0x00, 0x03][..], &adu.pdu[..]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn given_ascii_request_with_wrong_lrc_then_drop_it() {
+        let mut codec = AduCodec::new(Framing::Ascii);
+        let mut buf = BytesMut::from(&b":1103006B00037F\r\n:1103006B0003"[..]);
+        assert_eq!(None, codec.decode(&mut buf).unwrap());
+        assert_eq!(&b":1103006B0003"[..], &buf[..]);
+        assert_eq!(1, codec.take_comm_errors());
+    }
+
+    #[test]
+    fn given_ascii_response_then_encode_with_lrc() {
+        let mut codec = AduCodec::new(Framing::Ascii);
+        let mut buf = BytesMut::new();
+        let adu = Adu {
+            header: AduHeader::Ascii { unit_id: 0x11 },
+            pdu: Bytes::from_static(&[0x03, 0x00, 0x6B, 0x00, 0x03]),
+        };
+        codec.encode(adu, &mut buf).unwrap();
+        assert_eq!(&b":1103006B00037E\r\n"[..], &buf[..]);
+    }
+
+    #[test]
+    fn given_rtu_response_then_decode_by_response_layout() {
+        let mut codec = AduCodec::client(Framing::Rtu);
+        let mut buf = BytesMut::from(&[0x01, 0x03, 0x04, 0x00, 0x20, 0x00, 0x00, 0xFB, 0xF9][..]);
+        let adu = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(&[0x03, 0x04, 0x00, 0x20, 0x00, 0x00][..], &adu.pdu[..]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn given_corrupted_checksum_then_fail_to_decode() {
+        let mut buf = BytesMut::from(&b":1103006B00037E\r\n"[..]);
+        assert!(corrupt_checksum(Framing::Ascii, &mut buf));
+        assert_eq!(&b":1103006B000370\r\n"[..], &buf[..]);
+        let mut codec = AduCodec::new(Framing::Ascii);
+        assert_eq!(None, codec.decode(&mut buf).unwrap());
+
+        let mut buf = BytesMut::from(&[0x01, 0x03, 0x04, 0x00, 0x20, 0x00, 0x00, 0xFB, 0xF9][..]);
+        assert!(corrupt_checksum(Framing::Rtu, &mut buf));
+        assert_eq!(0x06, buf[8]);
+        assert!(!corrupt_checksum(Framing::Tcp, &mut buf));
+    }
+}
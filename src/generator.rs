@@ -0,0 +1,213 @@
+/* Generators of register values changing over time,
+ * to simulate live sensors and meters.
+ */
+use crate::util::{format_float, parse_number};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use tokio::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Waveform {
+    /// from "from" to "to" linearly over "period_s" seconds, then stays at "to"
+    Ramp { from: f64, to: f64, period_s: f64 },
+    /// from "min" to "max" linearly over "period_s" seconds, then starts over
+    Sawtooth { min: f64, max: f64, period_s: f64 },
+    /// "offset" + "amplitude" * sin(2 * pi * t / "period_s")
+    Sine {
+        offset: f64,
+        amplitude: f64,
+        period_s: f64,
+    },
+    /// a random value from "min" to "max", or a random walk from the current value by at most
+    /// "step" within the bounds if specified
+    Random {
+        min: f64,
+        max: f64,
+        step: Option<f64>,
+    },
+    /// increases from the current value by "rate" per second
+    Totalizer { rate: f64 },
+    /// the values in turn, each held for "interval_s" seconds, then starts over
+    Steps {
+        values: Vec<String>,
+        interval_s: f64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Generator {
+    #[serde(flatten)]
+    pub waveform: Waveform,
+    /* the last value generated: when, its exact value and how it was written */
+    #[serde(skip)]
+    last: Option<(Duration, f64, String)>,
+}
+
+impl From<Waveform> for Generator {
+    fn from(waveform: Waveform) -> Self {
+        Generator {
+            waveform,
+            last: None,
+        }
+    }
+}
+
+impl Generator {
+    /// where a stateful waveform carries on from: the last value generated, unless the
+    /// current value has been written since
+    fn base(&self, elapsed: Duration, current: &str) -> Option<(Duration, f64)> {
+        match &self.last {
+            Some((t, value, written)) if written == current => Some((*t, *value)),
+            _ => parse_number(current).map(|value| (elapsed, value)),
+        }
+    }
+
+    /// the value at the given time since the server started, rounded to an integer if asked
+    /// to, or None to keep the current value
+    pub fn generate(&mut self, elapsed: Duration, current: &str, integer: bool) -> Option<String> {
+        let t = elapsed.as_secs_f64();
+        let value = match &self.waveform {
+            Waveform::Ramp { from, to, period_s } if *period_s > 0.0 => {
+                from + (to - from) * (t / period_s).min(1.0)
+            }
+            Waveform::Ramp { to, .. } => *to,
+            Waveform::Sawtooth { min, max, period_s } if *period_s > 0.0 => {
+                min + (max - min) * (t % period_s) / period_s
+            }
+            Waveform::Sawtooth { min, .. } => *min,
+            Waveform::Sine {
+                offset,
+                amplitude,
+                period_s,
+            } if *period_s > 0.0 => offset + amplitude * (2.0 * PI * t / period_s).sin(),
+            Waveform::Sine { offset, .. } => *offset,
+            Waveform::Random { min, max, step } => {
+                let (low, high) = (min.min(*max), max.max(*min));
+                let mut rng = rand::thread_rng();
+                match step {
+                    Some(step) => {
+                        let (_, value) = self.base(elapsed, current)?;
+                        (value + rng.gen_range(-step.abs()..=step.abs())).clamp(low, high)
+                    }
+                    None => rng.gen_range(low..=high),
+                }
+            }
+            Waveform::Totalizer { rate } => {
+                let (since, value) = self.base(elapsed, current)?;
+                value
+                    + rate
+                        * elapsed
+                            .saturating_sub(since)
+                            .as_secs_f64()
+            }
+            Waveform::Steps { values, interval_s } => {
+                if values.is_empty() || *interval_s <= 0.0 {
+                    return None;
+                }
+                return Some(values[(t / interval_s) as usize % values.len()].clone());
+            }
+        };
+        let written = if integer {
+            (value.round() as i64).to_string()
+        } else {
+            format_float(value)
+        };
+        self.last = Some((elapsed, value, written.clone()));
+        Some(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(generator: &mut Generator, s: f64, current: &str) -> String {
+        generator
+            .generate(Duration::from_secs_f64(s), current, false)
+            .unwrap()
+    }
+
+    #[test]
+    fn given_periodic_waveforms_then_follow_time() {
+        let mut ramp = Generator::from(Waveform::Ramp {
+            from: 10.0,
+            to: 20.0,
+            period_s: 10.0,
+        });
+        assert_eq!("15", at(&mut ramp, 5.0, "0"));
+        assert_eq!("20", at(&mut ramp, 30.0, "0"));
+
+        let mut sawtooth = Generator::from(Waveform::Sawtooth {
+            min: 0.0,
+            max: 100.0,
+            period_s: 4.0,
+        });
+        assert_eq!("25", at(&mut sawtooth, 1.0, "0"));
+        assert_eq!("50", at(&mut sawtooth, 6.0, "0"));
+
+        let mut sine = Generator::from(Waveform::Sine {
+            offset: 50.0,
+            amplitude: 10.0,
+            period_s: 4.0,
+        });
+        assert_eq!("60", at(&mut sine, 1.0, "0"));
+        assert_eq!("40", at(&mut sine, 3.0, "0"));
+
+        let values = vec!["1".to_string(), "2".to_string(), "3".to_string()];
+        let mut steps = Generator::from(Waveform::Steps {
+            values,
+            interval_s: 2.0,
+        });
+        assert_eq!("2", at(&mut steps, 3.0, "0"));
+        assert_eq!("1", at(&mut steps, 6.5, "0"));
+    }
+
+    #[test]
+    fn given_totalizer_then_integrate_from_current_value() {
+        let mut totalizer = Generator::from(Waveform::Totalizer { rate: 0.4 });
+        let elapsed = Duration::from_secs;
+        assert_eq!(
+            Some("100".to_string()),
+            totalizer.generate(elapsed(0), "100", true)
+        );
+        assert_eq!(
+            Some("100".to_string()),
+            totalizer.generate(elapsed(1), "100", true)
+        );
+        assert_eq!(
+            Some("101".to_string()),
+            totalizer.generate(elapsed(2), "100", true)
+        );
+        /* written in the meantime, hence reset */
+        assert_eq!(
+            Some("0".to_string()),
+            totalizer.generate(elapsed(3), "0", true)
+        );
+        assert_eq!(
+            Some("2".to_string()),
+            totalizer.generate(elapsed(8), "0", true)
+        );
+    }
+
+    #[test]
+    fn given_random_walk_then_stay_within_bounds() {
+        let mut random = Generator::from(Waveform::Random {
+            min: 0.0,
+            max: 10.0,
+            step: Some(1.0),
+        });
+        let mut current = "5".to_string();
+        for s in 0..100 {
+            let next = at(&mut random, s as f64, &current);
+            let (before, after) = (
+                parse_number(&current).unwrap(),
+                parse_number(&next).unwrap(),
+            );
+            assert!((0.0..=10.0).contains(&after));
+            assert!((after - before).abs() <= 1.0 + 1e-6);
+            current = next;
+        }
+    }
+}
@@ -0,0 +1,263 @@
+/* A long-running helper program next to a Modbus server,
+ * notified of the writes and setting values at any time, with JSON lines over its stdin/stdout.
+ */
+use crate::{config::*, program::*, types::*};
+use anyhow::{self, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, process::Stdio, sync::Arc};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::{mpsc, RwLock};
+
+/// an event sent to the helper program
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum HelperEvent {
+    /// the values of all the registers and coils when the server starts
+    Start {
+        registers: BTreeMap<u16, String>,
+        coils: BTreeMap<u16, bool>,
+    },
+    /// a request has written registers or coils, along with the values of those accessed
+    Write {
+        function_code: u8,
+        address: u16,
+        quantity: u16,
+        registers: BTreeMap<u16, String>,
+        coils: BTreeMap<u16, bool>,
+    },
+}
+
+impl HelperEvent {
+    pub fn start(server: &ModbusServerConfig) -> Self {
+        HelperEvent::Start {
+            registers: server
+                .register_data
+                .values_in(u16::MIN, u16::MAX),
+            coils: server
+                .coil_data
+                .values(&server.register_data)
+                .collect(),
+        }
+    }
+
+    /// the write to the addresses from first to last, by a request with the function code
+    pub fn write(server: &ModbusServerConfig, function_code: u8, first: u16, last: u16) -> Self {
+        let is_coil_write = matches!(function_code, 0x05 | 0x0F);
+        let (registers, coils) = if is_coil_write {
+            let coils = server
+                .coil_data
+                .values(&server.register_data)
+                .filter(|(addr, _)| (first..=last).contains(addr))
+                .collect();
+            (BTreeMap::new(), coils)
+        } else {
+            (
+                server
+                    .register_data
+                    .values_in(first, last),
+                BTreeMap::new(),
+            )
+        };
+        HelperEvent::Write {
+            function_code,
+            address: first,
+            quantity: last - first + 1,
+            registers,
+            coils,
+        }
+    }
+}
+
+/// a command received from the helper program
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum HelperCommand {
+    /// sets the data value of a register data item, given as a string or a number
+    SetRegister { address: u16, value: Value },
+    /// sets the value of a coil
+    SetCoil { address: u16, value: bool },
+}
+
+impl HelperCommand {
+    pub fn apply(self, server: &mut ModbusServerConfig) -> Result<(), ModbusExceptionCode> {
+        match self {
+            HelperCommand::SetRegister { address, value } => {
+                let value = match value {
+                    Value::String(value) => value,
+                    Value::Number(value) => value.to_string(),
+                    _ => return Err(ModbusExceptionCode::IllegalDataValue),
+                };
+                server
+                    .register_data
+                    .set_data_value(address, value)
+            }
+            HelperCommand::SetCoil { address, value } => {
+                if server
+                    .coil_data
+                    .set_value(address, value, &mut server.register_data)
+                {
+                    Ok(())
+                } else {
+                    Err(ModbusExceptionCode::IllegalDataAddress)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HelperHandle {
+    events: mpsc::UnboundedSender<String>,
+}
+
+impl HelperHandle {
+    /// queues the event for the helper program, unless it has exited, without waiting for it
+    pub fn send(&self, event: &HelperEvent) {
+        if let Ok(line) = serde_json::to_string(event) {
+            let _ = self.events.send(line);
+        }
+    }
+}
+
+/// starts the helper program of the server with the given unit ID, which then exchanges JSON
+/// lines with the server for as long as it runs
+pub fn start_helper(
+    program: &str,
+    unit_id: Option<u8>,
+    db: Arc<RwLock<ModbusDeviceConfig>>,
+) -> anyhow::Result<HelperHandle> {
+    let mut child = Command::new(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("failed to execute {}", program))?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .context("no stdin of the helper program")?;
+    let stdout = child
+        .stdout
+        .take()
+        .context("no stdout of the helper program")?;
+
+    let (events, mut rx) = mpsc::unbounded_channel::<String>();
+    tokio::spawn(async move {
+        while let Some(mut line) = rx.recv().await {
+            line.push('\n');
+            if stdin
+                .write_all(line.as_bytes())
+                .await
+                .is_err()
+                || stdin.flush().await.is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let program = program.to_string();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stdout).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let command = match serde_json::from_str::<HelperCommand>(line) {
+                Ok(command) => command,
+                Err(e) => {
+                    println!("invalid command from {}: {}: {}", program, line, e);
+                    continue;
+                }
+            };
+            let mut db = db.write().await;
+            let verbose_mode = db.verbose_mode;
+            if let Some(server) = db.unit_mut(unit_id) {
+                let watched = WatchedValues::take(server);
+                if let Err(e) = command.apply(server) {
+                    println!("failed in {} from {}: {:?}", line, program, e);
+                }
+                watched.run_changed(server, verbose_mode);
+            }
+        }
+        match child.wait().await {
+            Ok(status) => println!("helper program {} exited with {}", program, status),
+            Err(e) => println!("failed in waiting for {}: {}", program, e),
+        }
+    });
+    Ok(HelperHandle { events })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_server() -> ModbusServerConfig {
+        serde_yaml::from_str(
+            "
+            register_data:
+                db: {
+                    40001: { data_description: Setpoint, data_model_type: HoldingRegister,
+                             data_type: Uint16, data_value: 7 },
+                    40002: { data_description: Tag, data_model_type: HoldingRegister,
+                             data_type: String, data_value: AB },
+                }
+            coil_data:
+                db: {
+                    1: { data_description: Running, data_model_type: Coils,
+                         data_value: { type: Independent, value: false } },
+                }
+            ",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn given_commands_then_set_values() {
+        let mut server = build_server();
+        let command = |line| serde_json::from_str::<HelperCommand>(line).unwrap();
+        assert_eq!(
+            Ok(()),
+            command(r#"{"command": "set_register", "address": 40001, "value": 42}"#)
+                .apply(&mut server)
+        );
+        assert_eq!(
+            Ok(()),
+            command(r#"{"command": "set_register", "address": 40002, "value": "CD"}"#)
+                .apply(&mut server)
+        );
+        assert_eq!(
+            Err(ModbusExceptionCode::IllegalDataValue),
+            command(r#"{"command": "set_register", "address": 40001, "value": "-1"}"#)
+                .apply(&mut server)
+        );
+        assert_eq!(
+            Ok(()),
+            command(r#"{"command": "set_coil", "address": 1, "value": true}"#).apply(&mut server)
+        );
+        assert_eq!(
+            Err(ModbusExceptionCode::IllegalDataAddress),
+            command(r#"{"command": "set_coil", "address": 2, "value": true}"#).apply(&mut server)
+        );
+        assert_eq!(
+            r#"{"event":"start","registers":{"40001":"42","40002":"CD"},"coils":{"1":true}}"#,
+            serde_json::to_string(&HelperEvent::start(&server)).unwrap()
+        );
+    }
+
+    #[test]
+    fn given_write_then_report_values_accessed() {
+        let server = build_server();
+        assert_eq!(
+            r#"{"event":"write","function_code":16,"address":40001,"quantity":1,"registers":{"40001":"7"},"coils":{}}"#,
+            serde_json::to_string(&HelperEvent::write(&server, 0x10, 40001, 40001)).unwrap()
+        );
+        assert_eq!(
+            r#"{"event":"write","function_code":5,"address":1,"quantity":1,"registers":{},"coils":{"1":false}}"#,
+            serde_json::to_string(&HelperEvent::write(&server, 0x05, 1, 1)).unwrap()
+        );
+    }
+}
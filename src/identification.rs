@@ -0,0 +1,251 @@
+/* Device identification objects of a Modbus server,
+ * as read by Read Device Identification (function code 0x2B, MEI type 0x0E).
+ */
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const MEI_READ_DEVICE_ID: u8 = 0x0E;
+/// "a PDU is limited to 253 bytes", minus the function code and the response header
+const MAX_OBJECTS_LEN: usize = 253 - 7;
+/// the longest value of an object that fits in a single response
+const MAX_OBJECT_VALUE_LEN: usize = MAX_OBJECTS_LEN - 2;
+const MORE_FOLLOWS: u8 = 0xFF;
+/// the conformity level bit telling individual access is supported
+const INDIVIDUAL_ACCESS: u8 = 0x80;
+const LAST_REGULAR_OBJECT_ID: u8 = 0x06;
+const FIRST_EXTENDED_OBJECT_ID: u8 = 0x80;
+
+/// the names of the basic and regular objects, indexed by object ID
+pub const OBJECT_NAMES: [&str; 7] = [
+    "VendorName",
+    "ProductCode",
+    "MajorMinorRevision",
+    "VendorUrl",
+    "ProductName",
+    "ModelName",
+    "UserApplicationName",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceIdentification {
+    /* basic objects, which are mandatory */
+    pub vendor_name: String,
+    pub product_code: String,
+    pub major_minor_revision: String,
+    /* regular objects */
+    pub vendor_url: Option<String>,
+    pub product_name: Option<String>,
+    pub model_name: Option<String>,
+    pub user_application_name: Option<String>,
+    /// extended objects, keyed by object ID from 0x80 to 0xFF
+    pub extended_objects: Option<BTreeMap<u8, String>>,
+}
+
+impl DeviceIdentification {
+    /// the objects present, ordered by object ID
+    fn objects(&self) -> Vec<(u8, &str)> {
+        let regular = [
+            &self.vendor_url,
+            &self.product_name,
+            &self.model_name,
+            &self.user_application_name,
+        ];
+        let mut objects = vec![
+            (0x00, self.vendor_name.as_str()),
+            (0x01, self.product_code.as_str()),
+            (0x02, self.major_minor_revision.as_str()),
+        ];
+        objects.extend(
+            regular
+                .iter()
+                .zip(0x03..)
+                .filter_map(|(value, id)| value.as_deref().map(|v| (id, v))),
+        );
+        if let Some(extended) = &self.extended_objects {
+            objects.extend(
+                extended
+                    .iter()
+                    .filter(|(id, _)| **id >= FIRST_EXTENDED_OBJECT_ID)
+                    .map(|(id, v)| (*id, v.as_str())),
+            );
+        }
+        objects
+    }
+
+    fn conformity_level(&self) -> ReadDeviceIdCode {
+        let objects = self.objects();
+        match objects.last() {
+            Some((id, _)) if *id >= FIRST_EXTENDED_OBJECT_ID => ReadDeviceIdCode::Extended,
+            Some((id, _)) if *id > 0x02 => ReadDeviceIdCode::Regular,
+            _ => ReadDeviceIdCode::Basic,
+        }
+    }
+
+    /// the response data of Read Device Identification, following the MEI type
+    pub fn read(&self, read_code: u8, object_id: u8) -> Result<Vec<u8>, ModbusExceptionCode> {
+        let read_code = ReadDeviceIdCode::try_from(read_code)
+            .map_err(|_| ModbusExceptionCode::IllegalDataValue)?;
+        let conformity_level = self.conformity_level();
+        let objects = self.objects();
+        let mut rsp = vec![
+            MEI_READ_DEVICE_ID,
+            read_code as u8,
+            conformity_level as u8 | INDIVIDUAL_ACCESS,
+            0x00,
+            0x00,
+            0x00,
+        ];
+        let push_object = |rsp: &mut Vec<u8>, id: u8, value: &str| {
+            let value = &value.as_bytes()[..value.len().min(MAX_OBJECT_VALUE_LEN)];
+            rsp.push(id);
+            rsp.push(value.len() as u8);
+            rsp.extend_from_slice(value);
+        };
+
+        if read_code == ReadDeviceIdCode::Specific {
+            let (id, value) = objects
+                .into_iter()
+                .find(|(id, _)| *id == object_id)
+                .ok_or(ModbusExceptionCode::IllegalDataAddress)?;
+            push_object(&mut rsp, id, value);
+            rsp[5] = 1;
+            return Ok(rsp);
+        }
+
+        /* a level above the conformity level is answered at the conformity level */
+        let last_id = match read_code.min(conformity_level) {
+            ReadDeviceIdCode::Basic => 0x02,
+            ReadDeviceIdCode::Regular => LAST_REGULAR_OBJECT_ID,
+            _ => 0xFF,
+        };
+        let stream: Vec<_> = objects
+            .into_iter()
+            .filter(|(id, _)| *id <= last_id)
+            .collect();
+        /* an unknown object ID restarts the stream from the beginning */
+        let start = stream
+            .iter()
+            .position(|(id, _)| *id == object_id)
+            .unwrap_or(0);
+        let mut objects_len = 0;
+        for (id, value) in &stream[start..] {
+            let object_len = 2 + value.len().min(MAX_OBJECT_VALUE_LEN);
+            if objects_len + object_len > MAX_OBJECTS_LEN {
+                rsp[3] = MORE_FOLLOWS;
+                rsp[4] = *id;
+                break;
+            }
+            objects_len += object_len;
+            push_object(&mut rsp, *id, value);
+            rsp[5] += 1;
+        }
+        Ok(rsp)
+    }
+}
+
+/// the objects in the response data of Read Device Identification, along with
+/// the more follows flag and the next object ID
+pub fn parse_objects(data: &[u8]) -> (Vec<(u8, String)>, bool, u8) {
+    let mut objects = Vec::new();
+    let (more_follows, next_object_id) = match data {
+        [_, _, _, more, next, ..] => (*more == MORE_FOLLOWS, *next),
+        _ => return (objects, false, 0),
+    };
+    let mut rest = data.get(6..).unwrap_or_default();
+    while let [id, len, tail @ ..] = rest {
+        let len = (*len as usize).min(tail.len());
+        objects.push((*id, String::from_utf8_lossy(&tail[..len]).into_owned()));
+        rest = &tail[len..];
+    }
+    (objects, more_follows, next_object_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_identification() -> DeviceIdentification {
+        serde_yaml::from_str(
+            "
+            vendor_name: YAMS
+            product_code: Y-01
+            major_minor_revision: V1.0
+            model_name: Simulator
+            extended_objects: { 128: Extra }
+            ",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn given_stream_access_then_return_objects_up_to_level() {
+        let id = build_identification();
+        let rsp = id.read(0x01, 0x00).unwrap();
+        assert_eq!(&[0x0E, 0x01, 0x83, 0x00, 0x00, 0x03], &rsp[..6]);
+        let (objects, more_follows, _) = parse_objects(&rsp);
+        assert!(!more_follows);
+        assert_eq!(
+            vec![0x00, 0x01, 0x02],
+            objects
+                .iter()
+                .map(|o| o.0)
+                .collect::<Vec<_>>()
+        );
+
+        let (objects, _, _) = parse_objects(&id.read(0x02, 0x00).unwrap());
+        assert_eq!((0x05, "Simulator".to_string()), objects[3]);
+        let (objects, _, _) = parse_objects(&id.read(0x03, 0x02).unwrap());
+        assert_eq!(
+            vec![0x02, 0x05, 0x80],
+            objects
+                .iter()
+                .map(|o| o.0)
+                .collect::<Vec<_>>()
+        );
+        /* an unknown object ID restarts from the first object */
+        let (objects, _, _) = parse_objects(&id.read(0x01, 0x42).unwrap());
+        assert_eq!(3, objects.len());
+    }
+
+    #[test]
+    fn given_specific_access_then_return_single_object() {
+        let id = build_identification();
+        assert_eq!(
+            vec![0x0E, 0x04, 0x83, 0x00, 0x00, 0x01, 0x80, 0x05, b'E', b'x', b't', b'r', b'a'],
+            id.read(0x04, 0x80).unwrap()
+        );
+        assert_eq!(
+            Err(ModbusExceptionCode::IllegalDataAddress),
+            id.read(0x04, 0x03)
+        );
+        assert_eq!(
+            Err(ModbusExceptionCode::IllegalDataValue),
+            id.read(0x05, 0x00)
+        );
+    }
+
+    #[test]
+    fn given_objects_beyond_pdu_then_stream_with_more_follows() {
+        let mut id = build_identification();
+        id.extended_objects = Some(
+            (0x80..0x84)
+                .map(|i| (i, "x".repeat(100)))
+                .collect(),
+        );
+        let rsp = id.read(0x03, 0x80).unwrap();
+        let (objects, more_follows, next_object_id) = parse_objects(&rsp);
+        assert!(more_follows);
+        assert_eq!(0x82, next_object_id);
+        assert_eq!(2, objects.len());
+        let (objects, more_follows, _) = parse_objects(&id.read(0x03, next_object_id).unwrap());
+        assert!(!more_follows);
+        assert_eq!(
+            vec![0x82, 0x83],
+            objects
+                .iter()
+                .map(|o| o.0)
+                .collect::<Vec<_>>()
+        );
+    }
+}
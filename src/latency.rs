@@ -0,0 +1,118 @@
+/* Response latency of a Modbus server,
+ * to simulate a real device taking its time to respond.
+ */
+use crate::fault::request_addresses;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+use tokio_modbus::prelude::Request;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Latency {
+    /// always the given milliseconds
+    Fixed { ms: u64 },
+    /// a random number of milliseconds, uniformly distributed in the given range
+    Uniform { min_ms: u64, max_ms: u64 },
+}
+
+impl Latency {
+    pub fn duration(&self) -> Duration {
+        let ms = match *self {
+            Latency::Fixed { ms } => ms,
+            Latency::Uniform { min_ms, max_ms } => {
+                rand::thread_rng().gen_range(min_ms.min(max_ms)..=max_ms.max(min_ms))
+            }
+        };
+        Duration::from_millis(ms)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressLatency {
+    /// the first address of the range
+    pub start_address: u16,
+    /// the last address of the range, or the start address if not specified
+    pub end_address: Option<u16>,
+    pub latency: Latency,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LatencyConfig {
+    /// the latency of any response not covered by the address ranges below
+    pub default: Option<Latency>,
+    /// the latencies of the requests accessing any address in a range, where the first
+    /// range matching takes effect
+    pub addresses: Option<Vec<AddressLatency>>,
+}
+
+impl LatencyConfig {
+    /// how long to wait before responding to the request, if at all
+    pub fn latency_for(&self, req: &Request) -> Option<Duration> {
+        let by_address = request_addresses(req).and_then(|(first, last)| {
+            self.addresses
+                .iter()
+                .flatten()
+                .find(|a| {
+                    first <= a.end_address.unwrap_or(a.start_address) && last >= a.start_address
+                })
+        });
+        by_address
+            .map(|a| a.latency)
+            .or(self.default)
+            .map(|latency| latency.duration())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_address_ranges_then_take_first_match_or_default() {
+        let config: LatencyConfig = serde_yaml::from_str(
+            "
+            default: { type: Fixed, ms: 20 }
+            addresses:
+                - { start_address: 40010, end_address: 40019, latency: { type: Fixed, ms: 150 } }
+                - { start_address: 40015, latency: { type: Fixed, ms: 5 } }
+            ",
+        )
+        .unwrap();
+        let read = |addr, cnt| Request::ReadHoldingRegisters(addr, cnt);
+        assert_eq!(
+            Some(Duration::from_millis(150)),
+            config.latency_for(&read(40008, 3))
+        );
+        assert_eq!(
+            Some(Duration::from_millis(150)),
+            config.latency_for(&read(40015, 1))
+        );
+        assert_eq!(
+            Some(Duration::from_millis(20)),
+            config.latency_for(&read(40001, 9))
+        );
+        assert_eq!(
+            Some(Duration::from_millis(20)),
+            config.latency_for(&Request::Custom(0x11, vec![]))
+        );
+        assert_eq!(None, LatencyConfig::default().latency_for(&read(40001, 1)));
+    }
+
+    #[test]
+    fn given_uniform_range_then_stay_within_range() {
+        let latency = Latency::Uniform {
+            min_ms: 5,
+            max_ms: 200,
+        };
+        for _ in 0..100 {
+            let ms = latency.duration().as_millis();
+            assert!((5..=200).contains(&ms));
+        }
+        let latency = Latency::Uniform {
+            min_ms: 30,
+            max_ms: 10,
+        };
+        assert!((10..=30).contains(&latency.duration().as_millis()));
+    }
+}
@@ -2,7 +2,18 @@
 mod client;
 mod config;
 mod data;
+mod diagnostics;
+mod fault;
 mod file;
+mod file_record;
+mod frame;
+mod generator;
+mod helper;
+mod identification;
+mod latency;
+mod model;
+mod program;
+mod script;
 mod server;
 mod types;
 mod util;
@@ -0,0 +1,280 @@
+/* Process models linking registers and coils together,
+ * to simulate a physical process behind a device, e.g. a valve driving a flowrate.
+ */
+use crate::{data::*, script::MAX_SCRIPT_OPERATIONS};
+use rhai::{Dynamic, Engine, Scope, AST};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::time::Duration;
+
+pub const DEFAULT_MODEL_TICK_MS: u64 = 100;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProcessModelConfig {
+    /// the interval between evaluations, in milliseconds
+    pub tick_ms: Option<u64>,
+    /// the expressions giving the register values, keyed by register address
+    pub registers: Option<HashMap<u16, String>>,
+    /// the expressions giving the coil values, keyed by coil address
+    pub coils: Option<HashMap<u16, String>>,
+    /* the compiled expressions, along with when the model was last evaluated since the server
+     * started */
+    #[serde(skip)]
+    compiled: Option<CompiledModel>,
+    #[serde(skip)]
+    last_tick: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+struct CompiledModel {
+    registers: Vec<(u16, AST)>,
+    coils: Vec<(u16, AST)>,
+}
+
+/// the engine evaluating the models, with a runaway expression stopped as a script would be
+pub fn build_model_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_SCRIPT_OPERATIONS);
+    engine
+}
+
+fn compile(
+    engine: &Engine,
+    expressions: &Option<HashMap<u16, String>>,
+) -> anyhow::Result<Vec<(u16, AST)>> {
+    expressions
+        .iter()
+        .flatten()
+        .map(|(addr, expression)| {
+            let ast = engine
+                .compile(expression)
+                .map_err(|e| anyhow::anyhow!("failed to compile the model of {}: {}", addr, e))?;
+            Ok((*addr, ast))
+        })
+        .collect()
+}
+
+/// the value of an expression as a number, where a boolean counts as 0 or 1
+fn as_number(value: &Dynamic) -> Option<f64> {
+    value
+        .as_float()
+        .ok()
+        .or_else(|| value.as_int().ok().map(|v| v as f64))
+        .or_else(|| {
+            value
+                .as_bool()
+                .ok()
+                .map(|v| v as u8 as f64)
+        })
+}
+
+impl ProcessModelConfig {
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(
+            self.tick_ms
+                .unwrap_or(DEFAULT_MODEL_TICK_MS)
+                .max(1),
+        )
+    }
+
+    /// compiles the expressions, once for all the evaluations
+    pub fn load(&mut self, engine: &Engine) -> anyhow::Result<()> {
+        self.compiled = Some(CompiledModel {
+            registers: compile(engine, &self.registers)?,
+            coils: compile(engine, &self.coils)?,
+        });
+        Ok(())
+    }
+
+    /// evaluates the expressions at the given time since the server started, once loaded and
+    /// a tick has passed since the last evaluation, all against the values before the evaluation
+    pub fn evaluate(
+        &mut self,
+        engine: &Engine,
+        elapsed: Duration,
+        rdb: &mut ModbusRegisterDatabase,
+        cdb: &mut ModbusCoilDatabase,
+    ) {
+        let Some(compiled) = &self.compiled else {
+            return;
+        };
+        let dt = match self.last_tick {
+            Some(last) if elapsed.saturating_sub(last) < self.tick() => return,
+            Some(last) => elapsed - last,
+            None => Duration::ZERO,
+        };
+        self.last_tick = Some(elapsed);
+
+        let mut scope = Scope::new();
+        scope.push_constant("t", elapsed.as_secs_f64());
+        scope.push_constant("dt", dt.as_secs_f64());
+        for (addr, value) in rdb.numbers() {
+            scope.push_constant(format!("r{}", addr), value);
+        }
+        for (addr, value) in cdb.values(rdb) {
+            scope.push_constant(format!("c{}", addr), value);
+        }
+        let mut eval =
+            |addr: &u16, ast: &AST| match engine.eval_ast_with_scope::<Dynamic>(&mut scope, ast) {
+                Ok(value) => match as_number(&value) {
+                    Some(value) => Some((*addr, value)),
+                    None => {
+                        println!("model of {} is not a number: {}", addr, value);
+                        None
+                    }
+                },
+                Err(e) => {
+                    println!("failed in evaluating the model of {}: {}", addr, e);
+                    None
+                }
+            };
+        let registers: Vec<_> = compiled
+            .registers
+            .iter()
+            .filter_map(|(addr, ast)| eval(addr, ast))
+            .collect();
+        let coils: Vec<_> = compiled
+            .coils
+            .iter()
+            .filter_map(|(addr, ast)| eval(addr, ast))
+            .collect();
+
+        for (addr, value) in registers {
+            if !rdb.set_number(addr, value) {
+                println!("failed in modelling register @ {} with {}", addr, value);
+            }
+        }
+        for (addr, value) in coils {
+            if !cdb.set_value(addr, value != 0.0, rdb) {
+                println!("no coil @ {} to model", addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ModbusServerConfig, types::*};
+
+    #[test]
+    fn given_tank_model_then_integrate_over_ticks() {
+        let config: ModbusServerConfig = serde_yaml::from_str(
+            "
+            register_data:
+                db: {
+                    40001: { data_description: Valve, data_model_type: HoldingRegister,
+                             data_type: Uint16, data_value: 100 },
+                    30001: { data_description: Inflow, data_model_type: InputRegister,
+                             data_type: Float32, data_value: 0 },
+                    30003: { data_description: Level, data_model_type: InputRegister,
+                             data_type: Float32, data_value: 10 },
+                }
+            coil_data:
+                db: {
+                    1: { data_description: Overflow, data_model_type: Coils,
+                         data_value: { type: Independent, value: false } },
+                }
+            model:
+                tick_ms: 1000
+                registers: {
+                    30001: \"r30001 + (r40001 * 0.1 - r30001) * min(dt / 2.0, 1.0)\",
+                    30003: \"r30003 + (r30001 - 1) * dt\",
+                }
+                coils: { 1: \"r30003 > 12\" }
+            ",
+        )
+        .unwrap();
+        let ModbusServerConfig {
+            mut register_data,
+            mut coil_data,
+            model,
+            ..
+        } = config;
+        let mut model = model.unwrap();
+        let engine = build_model_engine();
+        model.load(&engine).unwrap();
+        let mut at = |s| {
+            model.evaluate(
+                &engine,
+                Duration::from_secs(s),
+                &mut register_data,
+                &mut coil_data,
+            );
+            let values: HashMap<_, _> = register_data.numbers().collect();
+            let overflow = coil_data
+                .values(&register_data)
+                .next()
+                .unwrap()
+                .1;
+            (values[&30001], values[&30003], overflow)
+        };
+        assert_eq!((0.0, 10.0, false), at(0));
+        /* the flowrate lags behind the valve position, and the level follows the flowrate */
+        assert_eq!((5.0, 9.0, false), at(1));
+        assert_eq!((5.0, 9.0, false), at(1));
+        assert_eq!((7.5, 13.0, false), at(2));
+        assert_eq!((8.75, 19.5, true), at(3));
+    }
+
+    #[test]
+    fn given_values_out_of_range_then_clamp_them() {
+        let mut model: ProcessModelConfig = serde_yaml::from_str(
+            "
+            registers: { 40001: \"r40001 - 5\", 40002: \"r40002 * 1000\" }
+            ",
+        )
+        .unwrap();
+        let (mut register_data, mut coil_data): (ModbusRegisterDatabase, ModbusCoilDatabase) = (
+            serde_yaml::from_str(
+                "
+                db: {
+                    40001: { data_description: Level, data_model_type: HoldingRegister,
+                             data_type: Uint16, data_value: 3 },
+                    40002: { data_description: Pressure, data_model_type: HoldingRegister,
+                             data_type: Int16, data_value: 100 },
+                }
+                ",
+            )
+            .unwrap(),
+            serde_yaml::from_str("db: {}").unwrap(),
+        );
+        let engine = build_model_engine();
+        model.load(&engine).unwrap();
+        model.evaluate(&engine, Duration::ZERO, &mut register_data, &mut coil_data);
+        let values: HashMap<_, _> = register_data.numbers().collect();
+        assert_eq!((0.0, 32767.0), (values[&40001], values[&40002]));
+        assert_eq!(
+            Ok(vec![0x0000, 0x7FFF]),
+            register_data.request_u16_registers(
+                40001,
+                2,
+                FunctionCode::ReadHoldingRegisters,
+                EndiannessType::BigEndian
+            )
+        );
+    }
+
+    #[test]
+    fn given_runaway_expression_then_stop_it() {
+        let mut model: ProcessModelConfig = serde_yaml::from_str(
+            "
+            registers: { 40001: \"loop {}\" }
+            ",
+        )
+        .unwrap();
+        let (mut register_data, mut coil_data): (ModbusRegisterDatabase, ModbusCoilDatabase) = (
+            serde_yaml::from_str("db: { 40001: { data_description: Level, data_model_type: HoldingRegister, data_type: Uint16, data_value: 1 } }").unwrap(),
+            serde_yaml::from_str("db: {}").unwrap(),
+        );
+        let engine = build_model_engine();
+        model.load(&engine).unwrap();
+        model.evaluate(&engine, Duration::ZERO, &mut register_data, &mut coil_data);
+        assert_eq!(
+            vec![(40001, 1.0)],
+            register_data
+                .numbers()
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,85 @@
+/* External programs run for a single register/coil,
+ * each taking care of what happens upon a change of its value.
+ */
+use crate::{config::*, util::*};
+use std::collections::HashMap;
+use tokio::process::Command;
+
+/// a change of the value of a data item having its own external program
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueChange {
+    pub program: String,
+    pub address: u16,
+    pub old_value: String,
+    pub new_value: String,
+}
+
+/// the values of the data items having their own external program, taken before an update of
+/// any kind: a request, a model tick, a script timer, a generator or a helper command
+pub struct WatchedValues {
+    registers: HashMap<u16, (Vec<u16>, String)>,
+    coils: HashMap<u16, bool>,
+}
+
+impl WatchedValues {
+    pub fn take(server: &ModbusServerConfig) -> Self {
+        WatchedValues {
+            registers: server.register_data.watched_values(),
+            coils: server
+                .coil_data
+                .watched_values(&server.register_data),
+        }
+    }
+
+    /// runs the external programs of the data items changed since the values were taken
+    pub fn run_changed(self, server: &ModbusServerConfig, verbose_mode: bool) {
+        let changes = server
+            .register_data
+            .changes(self.registers)
+            .into_iter()
+            .chain(
+                server
+                    .coil_data
+                    .changes(self.coils, &server.register_data),
+            );
+        for change in changes {
+            run_external_program(&change, verbose_mode);
+        }
+    }
+}
+
+/// runs the external program of the changed data item, with the address, the old value and the
+/// new value as both arguments and environment variables, without waiting for it to finish
+pub fn run_external_program(change: &ValueChange, verbose_mode: bool) {
+    vprintln(
+        &format!(
+            "running external program: {} {} {} {}",
+            change.program, change.address, change.old_value, change.new_value
+        ),
+        verbose_mode,
+    );
+    let spawned = Command::new(&change.program)
+        .arg(change.address.to_string())
+        .arg(&change.old_value)
+        .arg(&change.new_value)
+        .env("YAMS_ADDRESS", change.address.to_string())
+        .env("YAMS_OLD_VALUE", &change.old_value)
+        .env("YAMS_NEW_VALUE", &change.new_value)
+        .spawn();
+    match spawned {
+        /* reaped aside, so that a slow program holds up no request */
+        Ok(mut child) => {
+            let program = change.program.clone();
+            tokio::spawn(async move {
+                match child.wait().await {
+                    Ok(status) if !status.success() => {
+                        println!("external program {} exited with {}", program, status)
+                    }
+                    Err(e) => println!("failed in waiting for {}: {}", program, e),
+                    _ => {}
+                }
+            });
+        }
+        Err(e) => println!("failed to execute {}: {}", change.program, e),
+    }
+}
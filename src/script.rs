@@ -0,0 +1,336 @@
+/* Scripts embedded in a Modbus server,
+ * to run the logic of a device upon reads, writes and timers without any external program.
+ */
+use crate::data::*;
+use anyhow::{self, Context};
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Scope, AST, FLOAT, INT};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{Arc, Mutex},
+};
+use tokio::time::Duration;
+
+pub const ON_READ: &str = "on_read";
+pub const ON_WRITE: &str = "on_write";
+pub const ON_TIMER: &str = "on_timer";
+
+/// the most operations a script runs in a single call, so that a runaway script can't hold the
+/// server forever
+pub const MAX_SCRIPT_OPERATIONS: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScriptConfig {
+    /// the script itself
+    pub source: Option<String>,
+    /// the file holding the script, when the source is not specified
+    pub file: Option<String>,
+    /// the interval between the calls to on_timer, in milliseconds
+    pub timer_ms: Option<u64>,
+    /* the compiled script, along with when on_timer was last called since the server started */
+    #[serde(skip)]
+    hook: Option<Arc<ScriptHook>>,
+    #[serde(skip)]
+    last_timer: Option<Duration>,
+}
+
+/// the register and coil values a script works on, written back once the script returns
+#[derive(Debug, Default)]
+struct ScriptData {
+    registers: HashMap<u16, FLOAT>,
+    coils: HashMap<u16, bool>,
+    written_registers: HashMap<u16, FLOAT>,
+    written_coils: HashMap<u16, bool>,
+}
+
+#[derive(Debug)]
+struct ScriptHook {
+    engine: Engine,
+    ast: AST,
+    data: Arc<Mutex<ScriptData>>,
+}
+
+fn to_address(addr: INT) -> Result<u16, Box<EvalAltResult>> {
+    u16::try_from(addr).map_err(|_| format!("invalid address {}", addr).into())
+}
+
+fn build_engine(data: &Arc<Mutex<ScriptData>>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_SCRIPT_OPERATIONS);
+
+    let d = data.clone();
+    engine.register_fn(
+        "get_register",
+        move |addr: INT| -> Result<FLOAT, Box<EvalAltResult>> {
+            let addr = to_address(addr)?;
+            d.lock()
+                .unwrap()
+                .registers
+                .get(&addr)
+                .copied()
+                .ok_or_else(|| format!("no numeric register @ {}", addr).into())
+        },
+    );
+    let d = data.clone();
+    let set_register = move |addr: INT, value: FLOAT| -> Result<(), Box<EvalAltResult>> {
+        let addr = to_address(addr)?;
+        let mut data = d.lock().unwrap();
+        if !data.registers.contains_key(&addr) {
+            return Err(format!("no numeric register @ {}", addr).into());
+        }
+        data.registers.insert(addr, value);
+        data.written_registers
+            .insert(addr, value);
+        Ok(())
+    };
+    let set_int_register = set_register.clone();
+    engine.register_fn("set_register", set_register);
+    engine.register_fn("set_register", move |addr: INT, value: INT| {
+        set_int_register(addr, value as FLOAT)
+    });
+    let d = data.clone();
+    engine.register_fn(
+        "get_coil",
+        move |addr: INT| -> Result<bool, Box<EvalAltResult>> {
+            let addr = to_address(addr)?;
+            d.lock()
+                .unwrap()
+                .coils
+                .get(&addr)
+                .copied()
+                .ok_or_else(|| format!("no coil @ {}", addr).into())
+        },
+    );
+    let d = data.clone();
+    engine.register_fn(
+        "set_coil",
+        move |addr: INT, value: bool| -> Result<(), Box<EvalAltResult>> {
+            let addr = to_address(addr)?;
+            let mut data = d.lock().unwrap();
+            if !data.coils.contains_key(&addr) {
+                return Err(format!("no coil @ {}", addr).into());
+            }
+            data.coils.insert(addr, value);
+            data.written_coils.insert(addr, value);
+            Ok(())
+        },
+    );
+    engine
+}
+
+impl ScriptHook {
+    /// runs the script with access to the data: the top-level statements when no function is
+    /// given, otherwise the function if the script defines it
+    fn run(
+        &self,
+        function: Option<(&str, impl FuncArgs)>,
+        rdb: &mut ModbusRegisterDatabase,
+        cdb: &mut ModbusCoilDatabase,
+    ) -> Result<(), Box<EvalAltResult>> {
+        if let Some((name, _)) = &function {
+            if !self
+                .ast
+                .iter_functions()
+                .any(|f| f.name == *name)
+            {
+                return Ok(());
+            }
+        }
+        *self.data.lock().unwrap() = ScriptData {
+            registers: rdb.numbers().collect(),
+            coils: cdb.values(rdb).collect(),
+            ..Default::default()
+        };
+        let result = match function {
+            Some((name, args)) => self
+                .engine
+                .call_fn_with_options::<Dynamic>(
+                    CallFnOptions::new().eval_ast(false),
+                    &mut Scope::new(),
+                    &self.ast,
+                    name,
+                    args,
+                )
+                .map(|_| ()),
+            None => self.engine.run_ast(&self.ast),
+        };
+        /* whatever was written before a failure still holds */
+        let data = std::mem::take(&mut *self.data.lock().unwrap());
+        for (addr, value) in data.written_registers {
+            if !rdb.set_number(addr, value) {
+                println!("failed in setting register @ {} to {}", addr, value);
+            }
+        }
+        for (addr, value) in data.written_coils {
+            cdb.set_value(addr, value, rdb);
+        }
+        result
+    }
+}
+
+impl ScriptConfig {
+    /// compiles the script and runs its top-level statements
+    pub fn load(
+        &mut self,
+        rdb: &mut ModbusRegisterDatabase,
+        cdb: &mut ModbusCoilDatabase,
+    ) -> anyhow::Result<()> {
+        let source = match (&self.source, &self.file) {
+            (Some(source), _) => source.clone(),
+            (None, Some(file)) => fs::read_to_string(file)
+                .with_context(|| format!("failed to read the script file {}", file))?,
+            (None, None) => anyhow::bail!("neither the source nor the file of the script is set"),
+        };
+        let data = Arc::new(Mutex::new(ScriptData::default()));
+        let engine = build_engine(&data);
+        let ast = engine
+            .compile(source)
+            .map_err(|e| anyhow::anyhow!("failed to compile the script: {}", e))?;
+        let hook = ScriptHook { engine, ast, data };
+        hook.run(None::<(&str, ())>, rdb, cdb)
+            .map_err(|e| anyhow::anyhow!("failed to run the script: {}", e))?;
+        self.hook = Some(Arc::new(hook));
+        Ok(())
+    }
+
+    pub fn timer(&self) -> Option<Duration> {
+        self.timer_ms
+            .map(|ms| Duration::from_millis(ms.max(1)))
+    }
+
+    /// calls the function of the script, if loaded and defining it
+    pub fn call(
+        &self,
+        name: &str,
+        args: impl FuncArgs,
+        rdb: &mut ModbusRegisterDatabase,
+        cdb: &mut ModbusCoilDatabase,
+    ) {
+        let Some(hook) = &self.hook else {
+            return;
+        };
+        if let Err(e) = hook.run(Some((name, args)), rdb, cdb) {
+            println!("failed in running {} of the script: {}", name, e);
+        }
+    }
+
+    /// calls on_timer with the time in seconds since the server started, once the timer has
+    /// passed since the last call
+    pub fn on_timer(
+        &mut self,
+        elapsed: Duration,
+        rdb: &mut ModbusRegisterDatabase,
+        cdb: &mut ModbusCoilDatabase,
+    ) {
+        let Some(timer) = self.timer() else {
+            return;
+        };
+        if self
+            .last_timer
+            .is_some_and(|last| elapsed.saturating_sub(last) < timer)
+        {
+            return;
+        }
+        self.last_timer = Some(elapsed);
+        self.call(ON_TIMER, (elapsed.as_secs_f64(),), rdb, cdb);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModbusServerConfig;
+
+    #[test]
+    fn given_script_then_run_hooks_against_data() {
+        let config: ModbusServerConfig = serde_yaml::from_str(
+            "
+            register_data:
+                db: {
+                    40001: { data_description: Setpoint, data_model_type: HoldingRegister,
+                             data_type: Uint16, data_value: 0 },
+                    40002: { data_description: Reads, data_model_type: HoldingRegister,
+                             data_type: Uint16, data_value: 0 },
+                    40003: { data_description: Uptime, data_model_type: HoldingRegister,
+                             data_type: Float32, data_value: 0 },
+                }
+            coil_data:
+                db: {
+                    1: { data_description: Running, data_model_type: Coils,
+                         data_value: { type: Independent, value: false } },
+                }
+            script:
+                timer_ms: 1000
+                source: \"
+                    set_register(40001, 20);
+                    fn on_read(function_code, address, quantity) {
+                        set_register(40002, get_register(40002) + quantity);
+                    }
+                    fn on_write(function_code, address, quantity) {
+                        set_coil(1, get_register(40001) > 50);
+                    }
+                    fn on_timer(t) {
+                        set_register(40003, t);
+                        set_register(99, 0);
+                    }
+                \"
+            ",
+        )
+        .unwrap();
+        let ModbusServerConfig {
+            mut register_data,
+            mut coil_data,
+            script,
+            ..
+        } = config;
+        let mut script = script.unwrap();
+        let value = |rdb: &ModbusRegisterDatabase, addr| {
+            rdb.numbers()
+                .find(|(a, _)| *a == addr)
+                .unwrap()
+                .1
+        };
+        script
+            .load(&mut register_data, &mut coil_data)
+            .unwrap();
+        assert_eq!(20.0, value(&register_data, 40001));
+
+        script.call(
+            ON_READ,
+            (3_i64, 40001_i64, 2_i64),
+            &mut register_data,
+            &mut coil_data,
+        );
+        assert_eq!(2.0, value(&register_data, 40002));
+
+        register_data.set_number(40001, 80.0);
+        script.call(
+            ON_WRITE,
+            (6_i64, 40001_i64, 1_i64),
+            &mut register_data,
+            &mut coil_data,
+        );
+        assert!(
+            coil_data
+                .values(&register_data)
+                .next()
+                .unwrap()
+                .1
+        );
+
+        /* what was written before the failure still holds */
+        script.on_timer(
+            Duration::from_millis(1500),
+            &mut register_data,
+            &mut coil_data,
+        );
+        assert_eq!(1.5, value(&register_data, 40003));
+        script.on_timer(
+            Duration::from_millis(2000),
+            &mut register_data,
+            &mut coil_data,
+        );
+        assert_eq!(1.5, value(&register_data, 40003));
+    }
+}
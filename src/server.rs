@@ -1,355 +1,1358 @@
-use crate::{config::*, file::*, types::*, util::*};
-use futures::future;
-use std::sync::{Arc, Mutex};
+use crate::{
+    config::*, fault::*, file::*, file_record::*, frame::*, helper::*, identification::*, model::*,
+    program::*, script::*, types::*, util::*,
+};
+use bytes::{Bytes, BytesMut};
+use futures::{future, SinkExt, StreamExt};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+use tokio::time::{sleep, Duration, Instant};
 use tokio_modbus::prelude::*;
-use tokio_modbus::server::{self, Service};
+use tokio_modbus::server::Service;
+use tokio_util::codec::{Encoder, Framed};
 
+/// a response to send, along with how to send it
+#[derive(Debug)]
+struct Reply {
+    rsp: Response,
+    /// the fault to inject when sending the response
+    fault: Option<FaultAction>,
+    /// how long to wait before sending the response
+    latency: Option<Duration>,
+}
+
+#[derive(Clone)]
 struct MbServer {
-    db: Arc<Mutex<ModbusDeviceConfig>>,
-    counter: Arc<Mutex<u16>>,
+    /// the state of the server(s), shared by all the connections of all the endpoints
+    db: Arc<RwLock<ModbusDeviceConfig>>,
+    counter: Arc<AtomicU16>,
+    /// when the server started, as the time base of the generated values
+    started: Instant,
+    /// saves the file records written to the local files
+    records_saver: Arc<RecordsSaver>,
 }
 
 impl Service for MbServer {
-    type Request = Request;
-    type Response = Response;
+    type Request = (AduHeader, Request);
+    /// no response is sent when None
+    type Response = Option<Reply>;
     type Error = std::io::Error;
-    type Future = future::Ready<Result<Self::Response, Self::Error>>;
-
-    fn call(&self, req: Self::Request) -> Self::Future {
-        /* since the tokio-mobus crate doesn't support server sending exception response (yet),
-         * the custom response type is used as a workaround to send exception response below.
-         */
-        let mut db = self.db.lock().unwrap();
-        let mut counter = self.counter.lock().unwrap();
-        let endianness = db.common.endianness;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + Sync>>;
+
+    fn call(&self, (header, req): Self::Request) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move { Ok(service.reply(header, req).await) })
+    }
+}
 
-        *counter += 1;
+impl MbServer {
+    async fn reply(&self, header: AduHeader, req: Request) -> Option<Reply> {
+        let counter = self
+            .counter
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(1);
         println!(
             "{}",
             ansi_term::Colour::Blue.paint(format!(">>{:04}>>", counter))
         );
-        vprintln(&format!("received request {:?}", req), db.verbose_mode);
+        let db = self.db.read().await;
+        let endianness = db.common.endianness;
+        let unit_id = header.unit_id();
+        let verbose_mode = db.verbose_mode;
+        vprintln(
+            &format!("received request {:?} for unit {}", req, unit_id),
+            verbose_mode,
+        );
 
-        let mut server = db.server.take().unwrap();
+        for server in db.servers() {
+            server
+                .diagnostics
+                .lock()
+                .count_bus_message();
+        }
 
-        let future = match req {
-            Request::ReadInputRegisters(addr, cnt) => {
-                match server
-                    .register_data
-                    .request_u16_registers(addr, cnt, FunctionCode::ReadInputRegisters, endianness)
-                {
-                    Ok(registers) => {
-                        vprint("Ok", ansi_term::Colour::Green, db.verbose_mode);
-                        vprintln(
-                            &format!(": input register values {:#06X?}", registers),
-                            db.verbose_mode,
-                        );
-                        future::ready(Ok(Response::ReadInputRegisters(registers)))
-                    }
-                    Err(e) => {
-                        vprint("Err", ansi_term::Colour::Red, db.verbose_mode);
-                        vprintln(&format!(": {:?} Exception", e), db.verbose_mode);
-                        future::ready(Ok(Response::Custom(
-                            FunctionCode::ReadInputRegisters.get_exception_code(),
-                            vec![e as u8],
-                        )))
-                    }
+        /* the unit ID 0 broadcasts over a serial line, while it may address a device directly
+         * over Modbus TCP */
+        let is_tcp = matches!(header, AduHeader::Tcp { .. });
+        if unit_id == BROADCAST_UNIT_ID && !is_tcp {
+            let is_broadcast = is_broadcast_request(&req);
+            if !is_broadcast {
+                vprintln(
+                    &format!("ignored broadcast request {:?}", req),
+                    verbose_mode,
+                );
+            }
+            for server in db.servers() {
+                let mut diagnostics = server.diagnostics.lock();
+                diagnostics.count_received(true);
+                diagnostics.count_no_response();
+            }
+            if !is_broadcast {
+                return None;
+            }
+            drop(db);
+            let mut runs = Vec::new();
+            let mut db = self.db.write().await;
+            for (unit, server) in db.units_mut() {
+                if !server.diagnostics.lock().listen_only {
+                    let (_, run) = handle_request(server, req.clone(), endianness, verbose_mode);
+                    runs.extend(run.map(|run| (unit, run)));
+                }
+            }
+            drop(db);
+            for (unit, run) in runs {
+                self.run_with_files(unit, run, verbose_mode)
+                    .await;
+            }
+            return None;
+        }
+
+        let is_addressed = unit_id == db.common.device_id
+            || matches!(
+                header,
+                AduHeader::Tcp {
+                    unit_id: BROADCAST_UNIT_ID | TCP_DIRECT_UNIT_ID,
+                    ..
+                }
+            );
+        let unit = match db.servers {
+            Some(_) => Some(unit_id),
+            None if is_addressed => None,
+            None => return unknown_unit_response(&header, &req, verbose_mode),
+        };
+        let Some(server) = db.unit(unit) else {
+            return unknown_unit_response(&header, &req, verbose_mode);
+        };
+
+        {
+            let mut diagnostics = server.diagnostics.lock();
+            diagnostics.count_received(false);
+            /* only a restart brings the server out of listen only mode */
+            let is_restart =
+                matches!(&req, Request::Custom(0x08, data) if data.starts_with(&[0x00, 0x01]));
+            if diagnostics.listen_only && !is_restart {
+                vprintln("ignored in listen only mode", verbose_mode);
+                diagnostics.count_no_response();
+                return None;
+            }
+        }
+        let function_code = Bytes::from(req.clone())[0];
+        let latency = server
+            .latency
+            .as_ref()
+            .and_then(|l| l.latency_for(&req));
+
+        /* the reads leaving the data as is are served side by side, anything else one at a time */
+        if is_side_effect_free(server, &req) {
+            let response = handle_read_request(server, req, endianness, verbose_mode);
+            return build_reply(server, function_code, response, None, latency);
+        }
+        drop(db);
+        let mut db = self.db.write().await;
+        let server = db.unit_mut(unit)?;
+        let watched = WatchedValues::take(server);
+        server
+            .register_data
+            .generate(self.started.elapsed());
+        watched.run_changed(server, verbose_mode);
+        let fault = server
+            .faults
+            .as_mut()
+            .and_then(|faults| find_fault(faults, function_code, &req));
+        if let Some(action) = fault {
+            println!(
+                "{} {:?}",
+                ansi_term::Colour::Purple.paint("fault injected:"),
+                action
+            );
+        }
+        let (response, run) = match fault {
+            Some(FaultAction::Drop) => (None, None),
+            Some(FaultAction::Exception { code }) => (
+                Some(Response::Custom(function_code | 0x80, vec![code as u8])),
+                None,
+            ),
+            _ => handle_request(server, req, endianness, verbose_mode),
+        };
+        let fault = fault.filter(FaultAction::is_on_the_wire);
+        let reply = build_reply(server, function_code, response, fault, latency);
+        let saves = server
+            .file_records
+            .as_mut()
+            .map(|files| self.records_saver.take(files))
+            .unwrap_or_default();
+        drop(db);
+        /* the response waits for the file records saved and the external program, but the other
+         * requests don't */
+        self.records_saver.save(saves).await;
+        if let Some(run) = run {
+            self.run_with_files(unit, run, verbose_mode)
+                .await;
+        }
+        reply
+    }
+
+    /// runs the external program of the server with the given unit ID, with the data shared
+    /// through the files and no lock held, then picks up what the program has changed
+    async fn run_with_files(&self, unit: Option<u8>, run: ExternalProgramRun, verbose_mode: bool) {
+        let Some(data) = run.run(verbose_mode).await else {
+            return;
+        };
+        let mut db = self.db.write().await;
+        if let Some(server) = db.unit_mut(unit) {
+            let watched = WatchedValues::take(server);
+            run.pick_up(server, data);
+            watched.run_changed(server, verbose_mode);
+        }
+    }
+
+    async fn count_comm_errors(&self, errors: u16) {
+        let db = self.db.read().await;
+        for server in db.servers() {
+            server
+                .diagnostics
+                .lock()
+                .count_comm_errors(errors);
+        }
+    }
+}
+
+/// counts the response sent by the server, if any, and how to send it
+fn build_reply(
+    server: &ModbusServerConfig,
+    function_code: u8,
+    response: Option<Response>,
+    fault: Option<FaultAction>,
+    latency: Option<Duration>,
+) -> Option<Reply> {
+    let mut diagnostics = server.diagnostics.lock();
+    match &response {
+        Some(Response::Custom(f, data)) if f & 0x80 != 0 => {
+            diagnostics.count_sent(function_code, data.first().copied())
+        }
+        Some(_) => diagnostics.count_sent(function_code, None),
+        None => diagnostics.count_no_response(),
+    }
+    response.map(|rsp| Reply {
+        rsp,
+        fault,
+        latency,
+    })
+}
+
+/// whether the request only reads the data of the server, leaving it as is, with no generator,
+/// fault or script changing anything upon the read
+fn is_side_effect_free(server: &ModbusServerConfig, req: &Request) -> bool {
+    matches!(
+        req,
+        Request::ReadCoils(..)
+            | Request::ReadDiscreteInputs(..)
+            | Request::ReadInputRegisters(..)
+            | Request::ReadHoldingRegisters(..)
+    ) && server.faults.is_none()
+        && server.script.is_none()
+        && !server.register_data.has_generators()
+}
+
+fn is_broadcast_request(req: &Request) -> bool {
+    matches!(
+        req,
+        Request::WriteSingleCoil(..)
+            | Request::WriteMultipleCoils(..)
+            | Request::WriteSingleRegister(..)
+            | Request::WriteMultipleRegisters(..)
+            | Request::Custom(0x16, _)
+    )
+}
+
+/// whether the request reads registers/coils, hence calls on_read of the script beforehand
+fn is_read_request(function_code: u8) -> bool {
+    matches!(function_code, 0x01..=0x04 | 0x17)
+}
+
+/// whether the request writes registers/coils, hence calls on_write of the script and runs the
+/// external program afterwards
+fn is_write_request(function_code: u8) -> bool {
+    matches!(function_code, 0x05 | 0x06 | 0x0F | 0x10 | 0x16 | 0x17)
+}
+
+/// handles the request, along with the run of the external program to follow a write, which is
+/// left to the caller to run without holding up the other requests
+fn handle_request(
+    server: &mut ModbusServerConfig,
+    req: Request,
+    endianness: EndiannessType,
+    verbose_mode: bool,
+) -> (Option<Response>, Option<ExternalProgramRun>) {
+    let function_code = Bytes::from(req.clone())[0];
+    let addresses = request_addresses(&req);
+    /* the hooks of the script take the function code, the first address and the quantity */
+    let hook_args = addresses.map(|(first, last)| {
+        (
+            function_code as rhai::INT,
+            first as rhai::INT,
+            (last - first) as rhai::INT + 1,
+        )
+    });
+    let watched = WatchedValues::take(server);
+    if let (Some(script), Some(args)) = (&server.script, hook_args) {
+        if is_read_request(function_code) {
+            script.call(
+                ON_READ,
+                args,
+                &mut server.register_data,
+                &mut server.coil_data,
+            );
+        }
+    }
+    let response = handle_data_request(server, req, endianness, verbose_mode);
+    let is_written = is_write_request(function_code)
+        && !matches!(&response, Some(Response::Custom(f, _)) if f & 0x80 != 0);
+    if let (true, Some(script), Some(args)) = (is_written, &server.script, hook_args) {
+        script.call(
+            ON_WRITE,
+            args,
+            &mut server.register_data,
+            &mut server.coil_data,
+        );
+    }
+    if let (true, Some(helper), Some((first, last))) = (is_written, &server.helper, addresses) {
+        helper.send(&HelperEvent::write(server, function_code, first, last));
+    }
+    let run = if is_written {
+        ExternalProgramRun::prepare(server)
+    } else {
+        None
+    };
+    watched.run_changed(server, verbose_mode);
+    (response, run)
+}
+
+/// handles the requests only reading the data, hence with the data shared
+fn handle_read_request(
+    server: &ModbusServerConfig,
+    req: Request,
+    endianness: EndiannessType,
+    verbose_mode: bool,
+) -> Option<Response> {
+    Some(match req {
+        Request::ReadInputRegisters(addr, cnt) => {
+            match server
+                .register_data
+                .request_u16_registers(addr, cnt, FunctionCode::ReadInputRegisters, endianness)
+            {
+                Ok(registers) => {
+                    vprint("Ok", ansi_term::Colour::Green, verbose_mode);
+                    vprintln(
+                        &format!(": input register values {:#06X?}", registers),
+                        verbose_mode,
+                    );
+                    Response::ReadInputRegisters(registers)
+                }
+                Err(e) => {
+                    vprint("Err", ansi_term::Colour::Red, verbose_mode);
+                    vprintln(&format!(": {:?} Exception", e), verbose_mode);
+                    Response::Custom(
+                        FunctionCode::ReadInputRegisters.get_exception_code(),
+                        vec![e as u8],
+                    )
+                }
+            }
+        }
+        Request::ReadHoldingRegisters(addr, cnt) => {
+            match server
+                .register_data
+                .request_u16_registers(addr, cnt, FunctionCode::ReadHoldingRegisters, endianness)
+            {
+                Ok(registers) => {
+                    vprint("Ok", ansi_term::Colour::Green, verbose_mode);
+                    vprintln(
+                        &format!(": holding register values {:#06X?}", registers),
+                        verbose_mode,
+                    );
+                    Response::ReadHoldingRegisters(registers)
+                }
+                Err(e) => {
+                    vprint("Err", ansi_term::Colour::Red, verbose_mode);
+                    vprintln(&format!(": {:?} Exception", e), verbose_mode);
+                    Response::Custom(
+                        FunctionCode::ReadHoldingRegisters.get_exception_code(),
+                        vec![e as u8],
+                    )
                 }
             }
-            Request::ReadHoldingRegisters(addr, cnt) => {
+        }
+        Request::ReadCoils(addr, cnt) => {
+            match server.coil_data.read_coils(
+                addr,
+                cnt,
+                FunctionCode::ReadCoils,
+                &server.register_data,
+            ) {
+                Ok(coils) => {
+                    vprint("Ok", ansi_term::Colour::Green, verbose_mode);
+                    vprintln(&format!(": coil values {:#06X?}", coils), verbose_mode);
+                    Response::ReadCoils(coils)
+                }
+                Err(e) => {
+                    vprint("Err", ansi_term::Colour::Red, verbose_mode);
+                    vprintln(&format!(": {:?} Exception", e), verbose_mode);
+                    Response::Custom(FunctionCode::ReadCoils.get_exception_code(), vec![e as u8])
+                }
+            }
+        }
+        Request::ReadDiscreteInputs(addr, cnt) => {
+            match server.coil_data.read_coils(
+                addr,
+                cnt,
+                FunctionCode::ReadDiscreteInputs,
+                &server.register_data,
+            ) {
+                Ok(coils) => {
+                    vprint("Ok", ansi_term::Colour::Green, verbose_mode);
+                    vprintln(&format!(": coil values {:#06X?}", coils), verbose_mode);
+                    Response::ReadDiscreteInputs(coils)
+                }
+                Err(e) => {
+                    vprint("Err", ansi_term::Colour::Red, verbose_mode);
+                    vprintln(&format!(": {:?} Exception", e), verbose_mode);
+                    Response::Custom(
+                        FunctionCode::ReadDiscreteInputs.get_exception_code(),
+                        vec![e as u8],
+                    )
+                }
+            }
+        }
+        _ => return None,
+    })
+}
+
+fn handle_data_request(
+    server: &mut ModbusServerConfig,
+    req: Request,
+    endianness: EndiannessType,
+    verbose_mode: bool,
+) -> Option<Response> {
+    /* since the tokio-mobus crate doesn't support server sending exception response (yet),
+     * the custom response type is used as a workaround to send exception response below.
+     */
+    Some(match req {
+        Request::Custom(function_code, data) => {
+            return handle_custom_request(server, function_code, &data, endianness, verbose_mode)
+        }
+        Request::ReadCoils(..)
+        | Request::ReadDiscreteInputs(..)
+        | Request::ReadInputRegisters(..)
+        | Request::ReadHoldingRegisters(..) => {
+            return handle_read_request(server, req, endianness, verbose_mode)
+        }
+        Request::WriteMultipleRegisters(addr, values) => {
+            match server
+                .register_data
+                .update_u16_registers(
+                    addr,
+                    values,
+                    FunctionCode::WriteMultipleRegisters,
+                    endianness,
+                ) {
+                Ok(reg_num) => {
+                    vprint("Ok", ansi_term::Colour::Green, verbose_mode);
+                    vprintln(&format!(": {} registers updated", reg_num), verbose_mode);
+                    Response::WriteMultipleRegisters(addr, reg_num as u16)
+                }
+                Err(e) => {
+                    vprint("Err", ansi_term::Colour::Red, verbose_mode);
+                    vprintln(&format!(": {:?} Exception", e), verbose_mode);
+                    Response::Custom(
+                        FunctionCode::WriteMultipleRegisters.get_exception_code(),
+                        vec![e as u8],
+                    )
+                }
+            }
+        }
+        Request::WriteSingleRegister(addr, value) => {
+            let values = vec![value];
+            match server
+                .register_data
+                .update_u16_registers(addr, values, FunctionCode::WriteSingleRegister, endianness)
+            {
+                Ok(_) => {
+                    vprint("Ok", ansi_term::Colour::Green, verbose_mode);
+                    vprintln("register updated", verbose_mode);
+                    Response::WriteSingleRegister(addr, value)
+                }
+                Err(e) => {
+                    vprint("Err", ansi_term::Colour::Red, verbose_mode);
+                    vprintln(&format!(": {:?} Exception", e), verbose_mode);
+                    Response::Custom(
+                        FunctionCode::WriteSingleRegister.get_exception_code(),
+                        vec![e as u8],
+                    )
+                }
+            }
+        }
+        Request::ReadWriteMultipleRegisters(read_addr, cnt, write_addr, values) => match server
+            .register_data
+            .update_u16_registers(
+                write_addr,
+                values,
+                FunctionCode::ReadWriteMultipleRegisters,
+                endianness,
+            ) {
+            Ok(_) => {
                 match server
                     .register_data
-                    .request_u16_registers(addr, cnt, FunctionCode::ReadHoldingRegisters, endianness)
-                {
+                    .request_u16_registers(
+                        read_addr,
+                        cnt,
+                        FunctionCode::ReadWriteMultipleRegisters,
+                        endianness,
+                    ) {
                     Ok(registers) => {
-                        vprint("Ok", ansi_term::Colour::Green, db.verbose_mode);
+                        vprint("Ok", ansi_term::Colour::Green, verbose_mode);
                         vprintln(
-                            &format!(": holding register values {:#06X?}", registers),
-                            db.verbose_mode,
+                            &format!(": after write, register values {:#06X?}", registers),
+                            verbose_mode,
                         );
-                        future::ready(Ok(Response::ReadHoldingRegisters(registers)))
+                        Response::ReadWriteMultipleRegisters(registers)
                     }
                     Err(e) => {
-                        vprint("Err", ansi_term::Colour::Red, db.verbose_mode);
-                        vprintln(&format!(": {:?} Exception", e), db.verbose_mode);
-                        future::ready(Ok(Response::Custom(
-                            FunctionCode::ReadHoldingRegisters.get_exception_code(),
+                        vprint("Err", ansi_term::Colour::Red, verbose_mode);
+                        vprintln(&format!(": {:?} Exception", e), verbose_mode);
+                        Response::Custom(
+                            FunctionCode::ReadWriteMultipleRegisters.get_exception_code(),
                             vec![e as u8],
-                        )))
+                        )
                     }
                 }
             }
-            Request::WriteMultipleRegisters(addr, values) => {
-                match server
-                    .register_data
-                    .update_u16_registers(addr, values, FunctionCode::WriteMultipleRegisters, endianness)
-                {
-                    Ok(reg_num) => {
-                        vprint("Ok", ansi_term::Colour::Green, db.verbose_mode);
-                        vprintln(&format!(": {} registers updated", reg_num), db.verbose_mode);
-                        if let Some(p) = &server.external_program {
-                            write_data_to_files(&server);
-                            vprintln(&format!("running external program: {}", p), db.verbose_mode);
-                            let _ = std::process::Command::new(p)
-                                .output()
-                                .expect(&format!("failed to execute {}", p));
-                            read_data_from_files(&mut server);
-                        }
-                        future::ready(Ok(Response::WriteMultipleRegisters(addr, reg_num as u16)))
-                    }
-                    Err(e) => {
-                        vprint("Err", ansi_term::Colour::Red, db.verbose_mode);
-                        vprintln(&format!(": {:?} Exception", e), db.verbose_mode);
-                        future::ready(Ok(Response::Custom(
-                            FunctionCode::WriteMultipleRegisters.get_exception_code(),
-                            vec![e as u8],
-                        )))
-                    }
-                }
+            Err(e) => {
+                vprint("Err", ansi_term::Colour::Red, verbose_mode);
+                vprintln(&format!(": {:?} Exception", e), verbose_mode);
+                Response::Custom(
+                    FunctionCode::WriteMultipleRegisters.get_exception_code(),
+                    vec![e as u8],
+                )
             }
-            Request::WriteSingleRegister(addr, value) => {
-                let values = vec![value];
-                match server
-                    .register_data
-                    .update_u16_registers(addr, values, FunctionCode::WriteSingleRegister, endianness)
-                {
-                    Ok(_) => {
-                        vprint("Ok", ansi_term::Colour::Green, db.verbose_mode);
-                        vprintln(&format!("register updated"), db.verbose_mode);
-                        if let Some(p) = &server.external_program {
-                            write_data_to_files(&server);
-                            vprintln(&format!("running external program: {}", p), db.verbose_mode);
-                            let _ = std::process::Command::new(p)
-                                .output()
-                                .expect(&format!("failed to execute {}", p));
-                            read_data_from_files(&mut server);
-                        }
-                        future::ready(Ok(Response::WriteSingleRegister(addr, value)))
-                    }
-                    Err(e) => {
-                        vprint("Err", ansi_term::Colour::Red, db.verbose_mode);
-                        vprintln(&format!(": {:?} Exception", e), db.verbose_mode);
-                        future::ready(Ok(Response::Custom(
-                            FunctionCode::WriteSingleRegister.get_exception_code(),
-                            vec![e as u8],
-                        )))
-                    }
+        },
+        Request::WriteMultipleCoils(addr, values) => {
+            match server.coil_data.update_coils(
+                addr,
+                values,
+                FunctionCode::WriteMultipleCoils,
+                &mut server.register_data,
+            ) {
+                Ok(coil_num) => {
+                    vprint("Ok", ansi_term::Colour::Green, verbose_mode);
+                    vprintln(&format!(": {} coils updated", coil_num), verbose_mode);
+                    Response::WriteMultipleCoils(addr, coil_num as u16)
+                }
+                Err(e) => {
+                    vprint("Err", ansi_term::Colour::Red, verbose_mode);
+                    vprintln(&format!(": {:?} Exception", e), verbose_mode);
+                    Response::Custom(
+                        FunctionCode::WriteMultipleCoils.get_exception_code(),
+                        vec![e as u8],
+                    )
                 }
             }
-            Request::ReadWriteMultipleRegisters(read_addr, cnt, write_addr, values) => match server
-                .register_data
-                .update_u16_registers(write_addr, values, FunctionCode::ReadWriteMultipleRegisters, endianness)
-            {
+        }
+        Request::WriteSingleCoil(addr, value) => {
+            match server.coil_data.update_coils(
+                addr,
+                vec![value],
+                FunctionCode::WriteSingleCoil,
+                &mut server.register_data,
+            ) {
                 Ok(_) => {
-                    match server
-                        .register_data
-                        .request_u16_registers(
-                            read_addr,
-                            cnt,
-                            FunctionCode::ReadWriteMultipleRegisters,
-                            endianness,
-                        ) {
-                        Ok(registers) => {
-                            vprint("Ok", ansi_term::Colour::Green, db.verbose_mode);
-                            vprintln(
-                                &format!(": after write, register values {:#06X?}", registers),
-                                db.verbose_mode,
-                            );
-                            if let Some(p) = &server.external_program {
-                                write_data_to_files(&server);
-                                vprintln(
-                                    &format!("running external program: {}", p),
-                                    db.verbose_mode,
-                                );
-                                let _ = std::process::Command::new(p)
-                                    .output()
-                                    .expect(&format!("failed to execute {}", p));
-                                read_data_from_files(&mut server);
-                            }
-                            future::ready(Ok(Response::ReadWriteMultipleRegisters(registers)))
-                        }
-                        Err(e) => {
-                            vprint("Err", ansi_term::Colour::Red, db.verbose_mode);
-                            vprintln(&format!(": {:?} Exception", e), db.verbose_mode);
-                            future::ready(Ok(Response::Custom(
-                                FunctionCode::ReadWriteMultipleRegisters.get_exception_code(),
-                                vec![e as u8],
-                            )))
-                        }
-                    }
+                    vprint("Ok", ansi_term::Colour::Green, verbose_mode);
+                    vprintln(&format!(": coil is set to {}", value), verbose_mode);
+                    Response::WriteSingleCoil(addr, value)
                 }
                 Err(e) => {
-                    vprint("Err", ansi_term::Colour::Red, db.verbose_mode);
-                    vprintln(&format!(": {:?} Exception", e), db.verbose_mode);
-                    future::ready(Ok(Response::Custom(
-                        FunctionCode::WriteMultipleRegisters.get_exception_code(),
+                    vprint("Err", ansi_term::Colour::Red, verbose_mode);
+                    vprintln(&format!(": {:?} Exception", e), verbose_mode);
+                    Response::Custom(
+                        FunctionCode::WriteSingleCoil.get_exception_code(),
                         vec![e as u8],
-                    )))
+                    )
+                }
+            }
+        }
+        other => {
+            let e = ModbusExceptionCode::IllegalFunction;
+            vprint("Err", ansi_term::Colour::Red, verbose_mode);
+            vprintln(&format!(": {:?} Exception", e), verbose_mode);
+            Response::Custom(Bytes::from(other)[0] | 0x80, vec![e as u8])
+        }
+    })
+}
+
+const MAX_FIFO_COUNT: usize = 31;
+
+/// handles the requests tokio-modbus doesn't know about
+fn handle_custom_request(
+    server: &mut ModbusServerConfig,
+    function_code: u8,
+    data: &[u8],
+    endianness: EndiannessType,
+    verbose_mode: bool,
+) -> Option<Response> {
+    let exception = |e: ModbusExceptionCode| {
+        vprint("Err", ansi_term::Colour::Red, verbose_mode);
+        vprintln(&format!(": {:?} Exception", e), verbose_mode);
+        Some(Response::Custom(function_code | 0x80, vec![e as u8]))
+    };
+    let ok = |data: Vec<u8>| {
+        vprint("Ok", ansi_term::Colour::Green, verbose_mode);
+        vprintln(&format!(": response data {:02X?}", data), verbose_mode);
+        Some(Response::Custom(function_code, data))
+    };
+    match function_code {
+        0x07 => ok(vec![server.exception_status.unwrap_or(0)]),
+        0x08 => {
+            if data.len() < 2 {
+                return exception(ModbusExceptionCode::IllegalDataValue);
+            }
+            let sub_function = u16::from_be_bytes([data[0], data[1]]);
+            let diagnosed = server
+                .diagnostics
+                .lock()
+                .diagnose(sub_function, &data[2..]);
+            match diagnosed {
+                Some(Ok(data)) => ok(data),
+                Some(Err(e)) => exception(e),
+                None => {
+                    vprintln("no response to the diagnostics request", verbose_mode);
+                    None
                 }
+            }
+        }
+        0x0B => ok(server
+            .diagnostics
+            .lock()
+            .comm_event_counter_response()),
+        0x0C => ok(server
+            .diagnostics
+            .lock()
+            .comm_event_log_response()),
+        0x11 => match &server.report_server_id {
+            Some(r) => {
+                let mut rsp = vec![r.server_id.len() as u8 + 1];
+                rsp.extend_from_slice(&r.server_id);
+                rsp.push(if r.run_indicator { 0xFF } else { 0x00 });
+                ok(rsp)
+            }
+            None => exception(ModbusExceptionCode::IllegalFunction),
+        },
+        0x14 => match &server.file_records {
+            Some(files) => match read_file_records(files, data) {
+                Ok(data) => ok(data),
+                Err(e) => exception(e),
             },
-            Request::WriteMultipleCoils(addr, values) => {
-                match server.coil_data.update_coils(
-                    addr,
-                    values,
-                    FunctionCode::WriteMultipleCoils,
-                    &mut server.register_data,
-                    endianness,
-                ) {
-                    Ok(coil_num) => {
-                        vprint("Ok", ansi_term::Colour::Green, db.verbose_mode);
-                        vprintln(&format!(": {} coils updated", coil_num), db.verbose_mode);
-                        if let Some(p) = &server.external_program {
-                            write_data_to_files(&server);
-                            vprintln(&format!("running external program: {}", p), db.verbose_mode);
-                            let _ = std::process::Command::new(p)
-                                .output()
-                                .expect(&format!("failed to execute {}", p));
-                            read_data_from_files(&mut server);
-                        }
-                        future::ready(Ok(Response::WriteMultipleCoils(addr, coil_num as u16)))
-                    }
-                    Err(e) => {
-                        vprint("Err", ansi_term::Colour::Red, db.verbose_mode);
-                        vprintln(&format!(": {:?} Exception", e), db.verbose_mode);
-                        future::ready(Ok(Response::Custom(
-                            FunctionCode::WriteMultipleCoils.get_exception_code(),
-                            vec![e as u8],
-                        )))
-                    }
+            None => exception(ModbusExceptionCode::IllegalFunction),
+        },
+        0x15 => match &mut server.file_records {
+            Some(files) => match write_file_records(files, data) {
+                Ok(data) => ok(data),
+                Err(e) => exception(e),
+            },
+            None => exception(ModbusExceptionCode::IllegalFunction),
+        },
+        0x18 => {
+            let [a0, a1] = *data else {
+                return exception(ModbusExceptionCode::IllegalDataValue);
+            };
+            let addr = u16::from_be_bytes([a0, a1]);
+            match server
+                .fifo_queues
+                .as_ref()
+                .and_then(|q| q.get(&addr))
+            {
+                /* "the queue count may not exceed 31" */
+                Some(queue) if queue.len() > MAX_FIFO_COUNT => {
+                    exception(ModbusExceptionCode::IllegalDataValue)
                 }
+                Some(queue) => {
+                    let mut rsp = ((queue.len() as u16 + 1) * 2)
+                        .to_be_bytes()
+                        .to_vec();
+                    rsp.extend_from_slice(&(queue.len() as u16).to_be_bytes());
+                    queue
+                        .iter()
+                        .for_each(|v| rsp.extend_from_slice(&v.to_be_bytes()));
+                    ok(rsp)
+                }
+                None => exception(ModbusExceptionCode::IllegalDataAddress),
             }
-            Request::ReadCoils(addr, cnt) => {
-                match server.coil_data.read_coils(
-                    addr,
-                    cnt,
-                    FunctionCode::ReadCoils,
-                    &server.register_data,
-                    endianness,
-                ) {
-                    Ok(coils) => {
-                        vprint("Ok", ansi_term::Colour::Green, db.verbose_mode);
-                        vprintln(&format!(": coil values {:#06X?}", coils), db.verbose_mode);
-                        future::ready(Ok(Response::ReadCoils(coils)))
-                    }
-                    Err(e) => {
-                        vprint("Err", ansi_term::Colour::Red, db.verbose_mode);
-                        vprintln(&format!(": {:?} Exception", e), db.verbose_mode);
-                        future::ready(Ok(Response::Custom(
-                            FunctionCode::ReadCoils.get_exception_code(),
-                            vec![e as u8],
-                        )))
-                    }
+        }
+        0x2B => match (data, &server.device_identification) {
+            ([MEI_READ_DEVICE_ID, read_code, object_id], Some(identification)) => {
+                match identification.read(*read_code, *object_id) {
+                    Ok(data) => ok(data),
+                    Err(e) => exception(e),
                 }
             }
-            Request::ReadDiscreteInputs(addr, cnt) => {
-                match server.coil_data.read_coils(
-                    addr,
-                    cnt,
-                    FunctionCode::ReadDiscreteInputs,
-                    &server.register_data,
-                    endianness,
-                ) {
-                    Ok(coils) => {
-                        vprint("Ok", ansi_term::Colour::Green, db.verbose_mode);
-                        vprintln(&format!(": coil values {:#06X?}", coils), db.verbose_mode);
-                        future::ready(Ok(Response::ReadDiscreteInputs(coils)))
-                    }
-                    Err(e) => {
-                        vprint("Err", ansi_term::Colour::Red, db.verbose_mode);
-                        vprintln(&format!(": {:?} Exception", e), db.verbose_mode);
-                        future::ready(Ok(Response::Custom(
-                            FunctionCode::ReadDiscreteInputs.get_exception_code(),
-                            vec![e as u8],
-                        )))
-                    }
+            ([MEI_READ_DEVICE_ID, ..], Some(_)) => exception(ModbusExceptionCode::IllegalDataValue),
+            _ => exception(ModbusExceptionCode::IllegalFunction),
+        },
+        0x16 => {
+            let [a0, a1, and0, and1, or0, or1] = *data else {
+                return exception(ModbusExceptionCode::IllegalDataValue);
+            };
+            let addr = u16::from_be_bytes([a0, a1]);
+            let and_mask = u16::from_be_bytes([and0, and1]);
+            let or_mask = u16::from_be_bytes([or0, or1]);
+            match server
+                .register_data
+                .mask_write_u16_register(addr, and_mask, or_mask, endianness)
+            {
+                Ok(value) => {
+                    vprint("Ok", ansi_term::Colour::Green, verbose_mode);
+                    vprintln(
+                        &format!(": register is set to {:#06X}", value),
+                        verbose_mode,
+                    );
+                    /* the normal response is an echo of the request */
+                    Some(Response::Custom(function_code, data.to_vec()))
                 }
+                Err(e) => exception(e),
             }
-            Request::WriteSingleCoil(addr, value) => {
-                match server.coil_data.update_coils(
-                    addr,
-                    vec![value],
-                    FunctionCode::WriteSingleCoil,
-                    &mut server.register_data,
-                    endianness,
-                ) {
-                    Ok(_) => {
-                        vprint("Ok", ansi_term::Colour::Green, db.verbose_mode);
-                        vprintln(&format!(": coil is set to {}", value), db.verbose_mode);
-                        if let Some(p) = &server.external_program {
-                            write_data_to_files(&server);
-                            vprintln(&format!("running external program: {}", p), db.verbose_mode);
-                            let _ = std::process::Command::new(p)
-                                .output()
-                                .expect(&format!("failed to execute {}", p));
-                            read_data_from_files(&mut server);
-                        }
-                        future::ready(Ok(Response::WriteSingleCoil(addr, value)))
-                    }
-                    Err(e) => {
-                        vprint("Err", ansi_term::Colour::Red, db.verbose_mode);
-                        vprintln(&format!(": {:?} Exception", e), db.verbose_mode);
-                        future::ready(Ok(Response::Custom(
-                                    FunctionCode::WriteSingleCoil.get_exception_code(),
-                                    vec![e as u8],
-                        )))
+        }
+        _ => match server
+            .custom_function_codes
+            .as_ref()
+            .and_then(|c| c.get(&function_code))
+        {
+            Some(CustomFunctionResponse::Echo) => ok(data.to_vec()),
+            Some(CustomFunctionResponse::Fixed { data }) => match parse_bytes(data) {
+                Some(data) => ok(data),
+                None => exception(ModbusExceptionCode::IllegalDataValue),
+            },
+            Some(CustomFunctionResponse::Registers {
+                start_address,
+                quantity,
+            }) => {
+                let word_at = |i: usize| {
+                    data.get(i..i + 2)
+                        .map(|w| u16::from_be_bytes([w[0], w[1]]))
+                };
+                let addr = start_address.or(word_at(0));
+                let cnt = quantity.or(word_at(2));
+                /* as many registers as a read holding registers response holds */
+                let (Some(addr), Some(cnt @ 1..=125)) = (addr, cnt) else {
+                    return exception(ModbusExceptionCode::IllegalDataValue);
+                };
+                match server
+                    .register_data
+                    .request_u16_registers(
+                        addr,
+                        cnt,
+                        FunctionCode::ReadHoldingRegisters,
+                        endianness,
+                    ) {
+                    Ok(registers) => {
+                        let mut rsp = vec![(registers.len() * 2) as u8];
+                        registers
+                            .iter()
+                            .for_each(|r| rsp.extend_from_slice(&r.to_be_bytes()));
+                        ok(rsp)
                     }
+                    Err(e) => exception(e),
                 }
-            },
-            _ => unimplemented!(),
+            }
+            None => exception(ModbusExceptionCode::IllegalFunction),
+        },
+    }
+}
+
+/// a bus device stays silent for a unit ID it doesn't serve, while a gateway reports the
+/// missing target device
+fn unknown_unit_response(header: &AduHeader, req: &Request, verbose_mode: bool) -> Option<Reply> {
+    vprint("Filtered", ansi_term::Colour::Yellow, verbose_mode);
+    vprintln(
+        &format!(": unit {} is not simulated", header.unit_id()),
+        verbose_mode,
+    );
+    match header {
+        AduHeader::Tcp { .. } => {
+            let function_code = Bytes::from(req.clone())[0];
+            let rsp = Response::Custom(
+                function_code + 0x80,
+                vec![ModbusExceptionCode::GatewayTargetDeviceFailedToRespond as u8],
+            );
+            Some(Reply {
+                rsp,
+                fault: None,
+                latency: None,
+            })
+        }
+        AduHeader::Rtu { .. } | AduHeader::Ascii { .. } => None,
+    }
+}
+
+async fn serve<T>(transport: T, framing: Framing, service: MbServer) -> std::io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = Framed::new(transport, AduCodec::new(framing));
+    while let Some(adu) = framed.next().await {
+        let comm_errors = framed.codec_mut().take_comm_errors();
+        if comm_errors > 0 {
+            service
+                .count_comm_errors(comm_errors)
+                .await;
+        }
+        let Adu { header, pdu } = adu?;
+        let function_code = pdu[0];
+        let response = match Request::try_from(pdu) {
+            Ok(req) => service.call((header, req)).await?,
+            Err(e) => {
+                println!("failed in decoding request: {}", e);
+                let rsp = Response::Custom(
+                    function_code | 0x80,
+                    vec![ModbusExceptionCode::IllegalDataValue as u8],
+                );
+                Some(Reply {
+                    rsp,
+                    fault: None,
+                    latency: None,
+                })
+            }
+        };
+        let Some(reply) = response else {
+            continue;
         };
-        db.server = Some(server);
-        future
+        /* only this connection waits, the others being served by their own tasks */
+        if let Some(latency) = reply.latency {
+            sleep(latency).await;
+        }
+        let mut adu = Adu {
+            header,
+            pdu: reply.rsp.into(),
+        };
+        match reply.fault {
+            Some(FaultAction::Delay { ms }) => sleep(Duration::from_millis(ms)).await,
+            Some(FaultAction::WrongTransactionId) => match &mut adu.header {
+                AduHeader::Tcp { transaction_id, .. } => {
+                    *transaction_id = transaction_id.wrapping_add(1)
+                }
+                _ => println!("no transaction ID to fault with {:?} framing", framing),
+            },
+            Some(action @ (FaultAction::CorruptCrc | FaultAction::Truncate)) => {
+                /* the frame is mangled after encoding, hence bypassing the sink */
+                let mut buf = BytesMut::new();
+                framed
+                    .codec_mut()
+                    .encode(adu, &mut buf)?;
+                if action == FaultAction::Truncate {
+                    buf.truncate(buf.len() / 2);
+                } else if !corrupt_checksum(framing, &mut buf) {
+                    println!("no checksum to fault with {:?} framing", framing);
+                }
+                framed.get_mut().write_all(&buf).await?;
+                framed.get_mut().flush().await?;
+                continue;
+            }
+            _ => {}
+        }
+        framed.send(adu).await?;
     }
+    Ok(())
 }
 
 pub async fn start_modbus_server(
-    config: ModbusDeviceConfig,
+    mut config: ModbusDeviceConfig,
 ) -> Result<(), Box<dyn std::error::Error>> {
     #[cfg(target_os = "windows")]
     let _enabled = ansi_term::enable_ansi_support();
 
     print_configuration(&config);
 
-    if config
-        .server
-        .as_ref()
-        .unwrap()
-        .external_program
-        .is_some()
-    {
-        let server = config.server.as_ref().unwrap();
-        write_data_to_files(&server);
+    let engine = build_model_engine();
+
+    for server in config.servers_mut() {
+        server.register_data.fix_data_lengths();
+        server
+            .register_data
+            .check_generators()?;
+        server
+            .register_data
+            .generate(Duration::ZERO);
+        if let Some(script) = server.script.as_mut() {
+            script.load(&mut server.register_data, &mut server.coil_data)?;
+        }
+        if let Some(model) = server.model.as_mut() {
+            model.load(&engine)?;
+        }
+        if let Some(files) = server.file_records.as_mut() {
+            load_file_records(files)?;
+        }
+        if server.external_program.is_some() {
+            write_data_to_files(server)?;
+        }
+    }
+
+    /* all the endpoints serve the same device(s), hence share the same state */
+    let endpoints = config.common.endpoints();
+    let service = MbServer {
+        db: Arc::new(RwLock::new(config)),
+        counter: Arc::new(AtomicU16::new(0)),
+        started: Instant::now(),
+        records_saver: Arc::default(),
+    };
+    let helper_programs: Vec<_> = service
+        .db
+        .read()
+        .await
+        .units()
+        .filter_map(|(unit_id, server)| Some((unit_id, server.helper_program.clone()?)))
+        .collect();
+    for (unit_id, program) in helper_programs {
+        let helper = start_helper(&program, unit_id, service.db.clone())?;
+        let mut db = service.db.write().await;
+        if let Some(server) = db.unit_mut(unit_id) {
+            helper.send(&HelperEvent::start(server));
+            server.helper = Some(helper);
+        }
+    }
+    tokio::spawn(run_ticks(service.clone()));
+    future::try_join_all(
+        endpoints
+            .into_iter()
+            .map(|endpoint| serve_endpoint(endpoint, service.clone())),
+    )
+    .await?;
+    Ok(())
+}
+
+/// evaluates the process models and calls the script timers of the server(s) on the shortest
+/// of their ticks, each skipping the ticks until its own has passed
+async fn run_ticks(service: MbServer) {
+    let tick = {
+        let db = service.db.read().await;
+        db.servers()
+            .flat_map(|server| {
+                let model = server
+                    .model
+                    .as_ref()
+                    .map(ProcessModelConfig::tick);
+                let timer = server
+                    .script
+                    .as_ref()
+                    .and_then(ScriptConfig::timer);
+                model.into_iter().chain(timer)
+            })
+            .min()
+    };
+    let Some(tick) = tick else {
+        return;
+    };
+    let engine = build_model_engine();
+    let mut interval = tokio::time::interval(tick);
+    loop {
+        interval.tick().await;
+        let mut db = service.db.write().await;
+        let elapsed = service.started.elapsed();
+        let verbose_mode = db.verbose_mode;
+        for server in db.servers_mut() {
+            let watched = WatchedValues::take(server);
+            if let Some(model) = server.model.as_mut() {
+                model.evaluate(
+                    &engine,
+                    elapsed,
+                    &mut server.register_data,
+                    &mut server.coil_data,
+                );
+            }
+            if let Some(script) = server.script.as_mut() {
+                script.on_timer(elapsed, &mut server.register_data, &mut server.coil_data);
+            }
+            watched.run_changed(server, verbose_mode);
+        }
     }
+}
 
-    match config.common.protocol_type {
-        ProtocolType::TCP => {
-            let ip_addr = config
-                .common
+async fn serve_endpoint(
+    endpoint: ModbusEndpointConfig,
+    service: MbServer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let framing = match endpoint.protocol_type {
+        ProtocolType::TCP => Framing::Tcp,
+        ProtocolType::RTU | ProtocolType::RtuOverTcp => Framing::Rtu,
+        ProtocolType::Ascii => Framing::Ascii,
+    };
+    match endpoint.protocol_type {
+        ProtocolType::TCP | ProtocolType::RtuOverTcp => {
+            let ip_addr = endpoint
                 .ip_address
                 .expect("IP address missing");
-            let server = server::tcp::Server::new(ip_addr);
-            server
-                .serve(move || {
-                    Ok(MbServer {
-                        db: Arc::new(Mutex::new(config.clone())),
-                        counter: Arc::new(Mutex::new(0)),
-                    })
-                })
-                .await
-                .unwrap();
+            let listener = TcpListener::bind(ip_addr).await?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let service = service.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, framing, service).await {
+                        println!("connection closed with error: {}", e);
+                    }
+                });
+            }
         }
-        ProtocolType::RTU => {
-            let serial = build_serial(&config).ok_or("failed in building the serial server")?;
-            let server = server::rtu::Server::new(serial);
-            server
-                .serve_forever(move || {
-                    Ok(MbServer {
-                        db: Arc::new(Mutex::new(config.clone())),
-                        counter: Arc::new(Mutex::new(0)),
-                    })
-                })
-                .await;
+        ProtocolType::RTU | ProtocolType::Ascii => {
+            let serial = build_serial(&endpoint).ok_or("failed in building the serial server")?;
+            serve(serial, framing, service).await?;
         }
     };
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, FutureExt};
+
+    fn build_server() -> MbServer {
+        build_service(
+            "
+            common: { protocol_type: RTU, device_type: Server, device_id: 2, endianness: BigEndian }
+            client: ~
+            server:
+                register_data:
+                    db: {
+                        40001: { data_description: Setpoint, data_model_type: HoldingOrInputRegister,
+                                 data_type: Uint16, data_value: 7 },
+                        40002: { data_description: Level, data_model_type: HoldingOrInputRegister,
+                                 data_type: Uint16, data_value: 0,
+                                 generator: { type: Steps, values: [\"0x0C\"], interval_s: 60.0 } },
+                        40003: { data_description: Writes, data_model_type: HoldingOrInputRegister,
+                                 data_type: Uint16, data_value: 0 },
+                    }
+                coil_data: { db: {} }
+                script:
+                    source: \"fn on_write(f, address, quantity) { set_register(40003, get_register(40003) + 1); }\"
+                report_server_id: { server_id: [0x59, 0x41], run_indicator: true }
+                fifo_queues: { 40100: [0x0102, 0x0304], 40200: [] }
+                custom_function_codes: {
+                    0x41: { type: Echo },
+                    0x42: { type: Fixed, data: \"01 02 AB\" },
+                    0x43: { type: Registers, start_address: 40001, quantity: 1 },
+                    0x44: { type: Registers },
+                }
+                faults: [
+                    { function_code: 0x01, times: 1, action: { type: Drop } },
+                    { function_code: 0x05, action: { type: Exception, code: ServerDeviceBusy } },
+                    { function_code: 0x02, action: { type: Delay, ms: 10 } },
+                ]
+            ",
+        )
+    }
+
+    fn build_service(config: &str) -> MbServer {
+        let mut config: ModbusDeviceConfig = serde_yaml::from_str(config).unwrap();
+        for server in config.servers_mut() {
+            if let Some(script) = server.script.as_mut() {
+                script
+                    .load(&mut server.register_data, &mut server.coil_data)
+                    .unwrap();
+            }
+        }
+        MbServer {
+            db: Arc::new(RwLock::new(config)),
+            counter: Arc::new(AtomicU16::new(0)),
+            started: Instant::now(),
+            records_saver: Arc::default(),
+        }
+    }
+
+    fn call(server: &MbServer, unit_id: u8, req: Request) -> Option<Response> {
+        block_on(server.call((AduHeader::Rtu { unit_id }, req)))
+            .unwrap()
+            .map(|reply| reply.rsp)
+    }
+
+    #[test]
+    fn given_request_to_other_unit_then_no_response() {
+        let server = build_server();
+        assert_eq!(
+            None,
+            call(&server, 3, Request::ReadHoldingRegisters(40001, 1))
+        );
+        assert_eq!(
+            Some(Response::ReadHoldingRegisters(vec![7])),
+            call(&server, 2, Request::ReadHoldingRegisters(40001, 1))
+        );
+    }
+
+    #[test]
+    fn given_generator_then_read_generated_value() {
+        let server = build_server();
+        assert_eq!(
+            Some(Response::ReadInputRegisters(vec![0x0C])),
+            call(&server, 2, Request::ReadInputRegisters(40002, 1))
+        );
+    }
+
+    #[test]
+    fn given_script_then_run_on_write_after_successful_write() {
+        let server = build_server();
+        call(&server, 2, Request::WriteSingleRegister(40001, 8));
+        call(&server, 2, Request::WriteSingleRegister(40009, 8));
+        assert_eq!(
+            Some(Response::ReadHoldingRegisters(vec![1])),
+            call(&server, 2, Request::ReadHoldingRegisters(40003, 1))
+        );
+    }
+
+    #[test]
+    fn given_broadcast_write_then_update_without_response() {
+        let server = build_server();
+        assert_eq!(
+            None,
+            call(&server, 0, Request::WriteSingleRegister(40001, 9))
+        );
+        assert_eq!(
+            None,
+            call(&server, 0, Request::ReadHoldingRegisters(40001, 1))
+        );
+        assert_eq!(
+            Some(Response::ReadHoldingRegisters(vec![9])),
+            call(&server, 2, Request::ReadHoldingRegisters(40001, 1))
+        );
+    }
+
+    #[test]
+    fn given_tcp_request_to_unit_0_then_respond_as_addressed() {
+        let server = build_server();
+        let tcp_call = |unit_id, req| {
+            let header = AduHeader::Tcp {
+                transaction_id: 1,
+                unit_id,
+            };
+            block_on(server.call((header, req)))
+                .unwrap()
+                .map(|reply| reply.rsp)
+        };
+        assert_eq!(
+            Some(Response::WriteSingleRegister(40001, 9)),
+            tcp_call(0, Request::WriteSingleRegister(40001, 9))
+        );
+        assert_eq!(
+            Some(Response::ReadHoldingRegisters(vec![9])),
+            tcp_call(0, Request::ReadHoldingRegisters(40001, 1))
+        );
+        assert_eq!(
+            Some(Response::Custom(0x83, vec![0x0B])),
+            tcp_call(3, Request::ReadHoldingRegisters(40001, 1))
+        );
+    }
+
+    #[test]
+    fn given_mask_write_then_echo_request() {
+        let server = build_server();
+        let pdu = vec![0x9C, 0x41, 0xFF, 0xF0, 0x00, 0x25];
+        assert_eq!(
+            Some(Response::Custom(0x16, pdu.clone())),
+            call(&server, 2, Request::Custom(0x16, pdu))
+        );
+        assert_eq!(
+            Some(Response::ReadHoldingRegisters(vec![0x05])),
+            call(&server, 2, Request::ReadHoldingRegisters(40001, 1))
+        );
+        assert_eq!(
+            Some(Response::Custom(0x96, vec![0x03])),
+            call(&server, 2, Request::Custom(0x16, vec![0x9C, 0x41]))
+        );
+    }
+
+    #[test]
+    fn given_report_server_id_then_return_id_and_run_indicator() {
+        let server = build_server();
+        assert_eq!(
+            Some(Response::Custom(0x11, vec![0x03, 0x59, 0x41, 0xFF])),
+            call(&server, 2, Request::Custom(0x11, vec![]))
+        );
+    }
+
+    #[test]
+    fn given_fifo_queue_then_return_count_and_values() {
+        let server = build_server();
+        assert_eq!(
+            Some(Response::Custom(
+                0x18,
+                vec![0x00, 0x06, 0x00, 0x02, 0x01, 0x02, 0x03, 0x04]
+            )),
+            call(&server, 2, Request::Custom(0x18, vec![0x9C, 0xA4]))
+        );
+        assert_eq!(
+            Some(Response::Custom(0x18, vec![0x00, 0x02, 0x00, 0x00])),
+            call(&server, 2, Request::Custom(0x18, vec![0x9D, 0x08]))
+        );
+        assert_eq!(
+            Some(Response::Custom(0x98, vec![0x02])),
+            call(&server, 2, Request::Custom(0x18, vec![0x9C, 0x41]))
+        );
+    }
+
+    #[test]
+    fn given_custom_function_codes_then_respond_by_rule() {
+        let server = build_server();
+        assert_eq!(
+            Some(Response::Custom(0x41, vec![0x12, 0x34])),
+            call(&server, 2, Request::Custom(0x41, vec![0x12, 0x34]))
+        );
+        assert_eq!(
+            Some(Response::Custom(0x42, vec![0x01, 0x02, 0xAB])),
+            call(&server, 2, Request::Custom(0x42, vec![]))
+        );
+        assert_eq!(
+            Some(Response::Custom(0x43, vec![0x02, 0x00, 0x07])),
+            call(&server, 2, Request::Custom(0x43, vec![]))
+        );
+        assert_eq!(
+            Some(Response::Custom(0x44, vec![0x02, 0x00, 0x07])),
+            call(
+                &server,
+                2,
+                Request::Custom(0x44, vec![0x9C, 0x41, 0x00, 0x01])
+            )
+        );
+        assert_eq!(
+            Some(Response::Custom(0xC4, vec![0x03])),
+            call(&server, 2, Request::Custom(0x44, vec![]))
+        );
+        assert_eq!(
+            Some(Response::Custom(0xC5, vec![0x01])),
+            call(&server, 2, Request::Custom(0x45, vec![]))
+        );
+    }
+
+    #[test]
+    fn given_faults_then_inject_them_per_request() {
+        let server = build_server();
+        assert_eq!(None, call(&server, 2, Request::ReadCoils(1, 1)));
+        assert_eq!(
+            Some(Response::Custom(0x81, vec![0x02])),
+            call(&server, 2, Request::ReadCoils(1, 1))
+        );
+        assert_eq!(
+            Some(Response::Custom(0x85, vec![0x06])),
+            call(&server, 2, Request::WriteSingleCoil(1, true))
+        );
+        let req = Request::ReadDiscreteInputs(10001, 1);
+        let reply = block_on(server.call((AduHeader::Rtu { unit_id: 2 }, req)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(FaultAction::Delay { ms: 10 }), reply.fault);
+    }
+
+    fn build_plain_server(external_program: &str) -> MbServer {
+        let data_file = |name: &str| {
+            std::env::temp_dir()
+                .join(name)
+                .display()
+                .to_string()
+        };
+        build_service(&format!(
+            "
+            common: {{ protocol_type: RTU, device_type: Server, device_id: 2, endianness: BigEndian }}
+            client: ~
+            server:
+                register_data:
+                    db: {{
+                        40001: {{ data_description: Setpoint, data_model_type: HoldingOrInputRegister,
+                                 data_type: Uint16, data_value: 7 }},
+                    }}
+                coil_data: {{ db: {{}} }}
+                register_data_file: {}
+                coil_data_file: {}
+                external_program: {}
+            ",
+            data_file("yams.test.register.data"),
+            data_file("yams.test.coil.data"),
+            external_program,
+        ))
+    }
+
+    #[test]
+    fn given_state_being_read_then_serve_reads_and_hold_writes() {
+        let server = build_plain_server("~");
+        let reading = block_on(server.db.read());
+        assert_eq!(
+            Some(Response::ReadHoldingRegisters(vec![7])),
+            call(&server, 2, Request::ReadHoldingRegisters(40001, 1))
+        );
+        let req = Request::WriteSingleRegister(40001, 8);
+        let mut write = server.call((AduHeader::Rtu { unit_id: 2 }, req));
+        assert!((&mut write).now_or_never().is_none());
+        drop(reading);
+        assert_eq!(
+            Some(Response::WriteSingleRegister(40001, 8)),
+            block_on(write)
+                .unwrap()
+                .map(|reply| reply.rsp)
+        );
+    }
+
+    #[tokio::test]
+    async fn given_failing_external_program_then_keep_serving() {
+        let server = build_plain_server("./test/no.such.program");
+        for value in [8, 9] {
+            let req = Request::WriteSingleRegister(40001, value);
+            let reply = server
+                .call((AduHeader::Rtu { unit_id: 2 }, req))
+                .await
+                .unwrap();
+            assert_eq!(
+                Some(Response::WriteSingleRegister(40001, value)),
+                reply.map(|reply| reply.rsp)
+            );
+        }
+        let req = Request::ReadHoldingRegisters(40001, 1);
+        let reply = server
+            .call((AduHeader::Rtu { unit_id: 2 }, req))
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(Response::ReadHoldingRegisters(vec![9])),
+            reply.map(|reply| reply.rsp)
+        );
+    }
+
+    #[tokio::test]
+    async fn given_slow_external_program_then_serve_reads_meanwhile() {
+        let server = build_plain_server("./test/slow.external.program.sh");
+        let req = Request::WriteSingleRegister(40001, 8);
+        let writing = tokio::spawn(server.call((AduHeader::Rtu { unit_id: 2 }, req)));
+        sleep(Duration::from_millis(200)).await;
+        let req = Request::ReadHoldingRegisters(40001, 1);
+        let reply = tokio::time::timeout(
+            Duration::from_millis(500),
+            server.call((AduHeader::Rtu { unit_id: 2 }, req)),
+        )
+        .await
+        .expect("the read waited for the external program")
+        .unwrap();
+        assert_eq!(
+            Some(Response::ReadHoldingRegisters(vec![8])),
+            reply.map(|reply| reply.rsp)
+        );
+        assert!(!writing.is_finished());
+        assert_eq!(
+            Some(Response::WriteSingleRegister(40001, 8)),
+            writing
+                .await
+                .unwrap()
+                .unwrap()
+                .map(|reply| reply.rsp)
+        );
+    }
+}
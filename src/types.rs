@@ -1,12 +1,46 @@
 use clap::ArgEnum;
 use serde::{Deserialize, Serialize};
 use std::io;
+use tokio_modbus::prelude::Response;
 use tokio_serial::{DataBits, Parity, StopBits};
 
-#[derive(ArgEnum, Clone, Copy, PartialEq, Debug, Deserialize)]
+/* The byte layouts of a 32-bit value 0xAABBCCDD (A being the most significant byte) on the wire.
+ * The same word/byte order applies to values of other sizes.
+ */
+#[derive(ArgEnum, Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
 pub enum EndiannessType {
+    /// ABCD: most significant word first
+    #[clap(alias = "abcd")]
+    #[serde(alias = "ABCD")]
     BigEndian,
+    /// CDAB: least significant word first
+    #[clap(alias = "cdab")]
+    #[serde(alias = "CDAB")]
     LittleEndian,
+    /// BADC: most significant word first, bytes swapped within each word
+    #[clap(alias = "badc")]
+    #[serde(alias = "BADC")]
+    BigEndianByteSwap,
+    /// DCBA: least significant word first, bytes swapped within each word
+    #[clap(alias = "dcba")]
+    #[serde(alias = "DCBA")]
+    LittleEndianByteSwap,
+}
+
+impl EndiannessType {
+    pub fn is_word_swapped(&self) -> bool {
+        matches!(
+            self,
+            EndiannessType::LittleEndian | EndiannessType::LittleEndianByteSwap
+        )
+    }
+
+    pub fn is_byte_swapped(&self) -> bool {
+        matches!(
+            self,
+            EndiannessType::BigEndianByteSwap | EndiannessType::LittleEndianByteSwap
+        )
+    }
 }
 
 #[derive(ArgEnum, Clone, PartialEq, Debug, Deserialize)]
@@ -15,10 +49,15 @@ pub enum DeviceType {
     Server,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(ArgEnum, Clone, Copy, PartialEq, Debug, Deserialize)]
 pub enum ProtocolType {
     RTU,
     TCP,
+    /// RTU frames tunnelled over a TCP connection
+    RtuOverTcp,
+    /// Modbus ASCII over a serial line
+    Ascii,
 }
 
 #[derive(ArgEnum, Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
@@ -26,10 +65,61 @@ pub enum DataType {
     Float32,
     Float64,
     Uint16,
+    Int16,
     Uint32,
     Uint64,
     Int32,
     Int64,
+    /// ASCII characters packed two per register, padded with NUL
+    #[clap(alias = "ascii")]
+    #[serde(alias = "Ascii")]
+    String,
+    /// raw bytes packed two per register
+    Bytes,
+    /// packed bits of up to 4 registers
+    BitField,
+    /// two unsigned 8-bit values in one register, the first one in the high byte
+    Uint8Pair,
+    /// two signed 8-bit values in one register, the first one in the high byte
+    Int8Pair,
+}
+
+impl DataType {
+    /// the number of registers taken by one value of the data type,
+    /// or None if the data type has a variable length
+    pub fn register_count(&self) -> Option<usize> {
+        match self {
+            DataType::Uint16 | DataType::Int16 => Some(1),
+            DataType::Uint8Pair | DataType::Int8Pair => Some(1),
+            DataType::Float32 | DataType::Uint32 | DataType::Int32 => Some(2),
+            DataType::Float64 | DataType::Uint64 | DataType::Int64 => Some(4),
+            DataType::String | DataType::Bytes | DataType::BitField => None,
+        }
+    }
+
+    /// the range of the values of an integer data type
+    pub fn integer_range(&self) -> Option<(f64, f64)> {
+        match self {
+            DataType::Uint16 => Some((u16::MIN as f64, u16::MAX as f64)),
+            DataType::Int16 => Some((i16::MIN as f64, i16::MAX as f64)),
+            DataType::Uint32 => Some((u32::MIN as f64, u32::MAX as f64)),
+            DataType::Int32 => Some((i32::MIN as f64, i32::MAX as f64)),
+            DataType::Uint64 => Some((u64::MIN as f64, u64::MAX as f64)),
+            DataType::Int64 => Some((i64::MIN as f64, i64::MAX as f64)),
+            _ => None,
+        }
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        !matches!(
+            self,
+            DataType::String
+                | DataType::Bytes
+                | DataType::BitField
+                | DataType::Uint8Pair
+                | DataType::Int8Pair
+        )
+    }
 }
 
 #[derive(ArgEnum, Clone, Debug, Copy, PartialEq, Serialize, Deserialize)]
@@ -54,10 +144,13 @@ pub enum ModbusRequestReturnType {
     ResultWithU16Vec(Result<Vec<u16>, io::Error>),
     ResultWithBoolVec(Result<Vec<bool>, io::Error>),
     ResultWithNothing(Result<(), io::Error>),
+    ResultWithResponse(Result<Response, io::Error>),
 }
 
 #[derive(ArgEnum, Clone, Copy, PartialEq, Debug, Deserialize)]
 pub enum FunctionCode {
+    /// a raw PDU with a user-defined function code, given along with the request
+    Custom = 0x00,
     ReadCoils = 0x01,
     ReadDiscreteInputs = 0x02,
     ReadHoldingRegisters = 0x03,
@@ -70,7 +163,13 @@ pub enum FunctionCode {
     GetcommEventLog = 0x0C,
     WriteMultipleCoils = 0x0F,
     WriteMultipleRegisters = 0x10,
+    ReportServerId = 0x11,
+    ReadFileRecord = 0x14,
+    WriteFileRecord = 0x15,
+    MaskWriteRegister = 0x16,
     ReadWriteMultipleRegisters = 0x17,
+    ReadFifoQueue = 0x18,
+    ReadDeviceIdentification = 0x2B,
 }
 
 impl FunctionCode {
@@ -79,14 +178,108 @@ impl FunctionCode {
     }
 }
 
+#[derive(ArgEnum, Clone, Copy, PartialEq, Debug, Deserialize)]
+pub enum DiagnosticsSubFunction {
+    ReturnQueryData = 0x00,
+    RestartCommunications = 0x01,
+    ReturnDiagnosticRegister = 0x02,
+    ForceListenOnlyMode = 0x04,
+    ClearCounters = 0x0A,
+    ReturnBusMessageCount = 0x0B,
+    ReturnBusCommunicationErrorCount = 0x0C,
+    ReturnBusExceptionErrorCount = 0x0D,
+    ReturnServerMessageCount = 0x0E,
+    ReturnServerNoResponseCount = 0x0F,
+    ReturnServerNakCount = 0x10,
+    ReturnServerBusyCount = 0x11,
+    ReturnBusCharacterOverrunCount = 0x12,
+    ClearOverrunCounter = 0x14,
+}
+
+impl TryFrom<u16> for DiagnosticsSubFunction {
+    type Error = u16;
+
+    fn try_from(code: u16) -> Result<Self, u16> {
+        use DiagnosticsSubFunction::*;
+        [
+            ReturnQueryData,
+            RestartCommunications,
+            ReturnDiagnosticRegister,
+            ForceListenOnlyMode,
+            ClearCounters,
+            ReturnBusMessageCount,
+            ReturnBusCommunicationErrorCount,
+            ReturnBusExceptionErrorCount,
+            ReturnServerMessageCount,
+            ReturnServerNoResponseCount,
+            ReturnServerNakCount,
+            ReturnServerBusyCount,
+            ReturnBusCharacterOverrunCount,
+            ClearOverrunCounter,
+        ]
+        .into_iter()
+        .find(|s| *s as u16 == code)
+        .ok_or(code)
+    }
+}
+
+/// the access type of a Read Device Identification request
+#[derive(ArgEnum, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Deserialize)]
+pub enum ReadDeviceIdCode {
+    Basic = 0x01,
+    Regular = 0x02,
+    Extended = 0x03,
+    Specific = 0x04,
+}
+
+impl TryFrom<u8> for ReadDeviceIdCode {
+    type Error = u8;
+
+    fn try_from(code: u8) -> Result<Self, u8> {
+        use ReadDeviceIdCode::*;
+        [Basic, Regular, Extended, Specific]
+            .into_iter()
+            .find(|c| *c as u8 == code)
+            .ok_or(code)
+    }
+}
+
 /* The tokio-modbus crate doesn't make the exception code public
  * hence the definitions below
  */
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ModbusExceptionCode {
     IllegalFunction = 0x01,
     IllegalDataAddress = 0x02,
     IllegalDataValue = 0x03,
+    ServerDeviceFailure = 0x04,
+    Acknowledge = 0x05,
+    ServerDeviceBusy = 0x06,
+    MemoryParityError = 0x08,
+    GatewayPathUnavailable = 0x0A,
+    GatewayTargetDeviceFailedToRespond = 0x0B,
+}
+
+impl TryFrom<u8> for ModbusExceptionCode {
+    type Error = u8;
+
+    fn try_from(code: u8) -> Result<Self, u8> {
+        use ModbusExceptionCode::*;
+        [
+            IllegalFunction,
+            IllegalDataAddress,
+            IllegalDataValue,
+            ServerDeviceFailure,
+            Acknowledge,
+            ServerDeviceBusy,
+            MemoryParityError,
+            GatewayPathUnavailable,
+            GatewayTargetDeviceFailedToRespond,
+        ]
+        .into_iter()
+        .find(|c| *c as u8 == code)
+        .ok_or(code)
+    }
 }
 
 #[derive(ArgEnum, Clone, Copy, PartialEq, Debug, Deserialize)]
@@ -96,9 +289,9 @@ pub enum ParityType {
     None,
 }
 
-impl Into<Parity> for ParityType {
-    fn into(self) -> Parity {
-        match self {
+impl From<ParityType> for Parity {
+    fn from(p: ParityType) -> Parity {
+        match p {
             ParityType::Even => Parity::Even,
             ParityType::Odd => Parity::Odd,
             ParityType::None => Parity::None,
@@ -112,9 +305,9 @@ pub enum StopBitsType {
     Two,
 }
 
-impl Into<StopBits> for StopBitsType {
-    fn into(self) -> StopBits {
-        match self {
+impl From<StopBitsType> for StopBits {
+    fn from(s: StopBitsType) -> StopBits {
+        match s {
             StopBitsType::One => StopBits::One,
             StopBitsType::Two => StopBits::Two,
         }
@@ -129,9 +322,9 @@ pub enum DataBitsType {
     Eight,
 }
 
-impl Into<DataBits> for DataBitsType {
-    fn into(self) -> DataBits {
-        match self {
+impl From<DataBitsType> for DataBits {
+    fn from(d: DataBitsType) -> DataBits {
+        match d {
             DataBitsType::Five => DataBits::Five,
             DataBitsType::Six => DataBits::Six,
             DataBitsType::Seven => DataBits::Seven,
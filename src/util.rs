@@ -1,23 +1,24 @@
 use crate::{config::*, types::*};
-use std::mem::transmute;
 
 pub fn print_configuration(config: &ModbusDeviceConfig) {
-    if let Some(_server) = &config.server {
-        println!("Modbus Server (ID: {})", config.common.device_id);
-        print!("runs {:?} ", config.common.protocol_type);
-        match config.common.protocol_type {
-            ProtocolType::TCP => {
-                println!("@ {}", config.common.ip_address.unwrap());
+    if config.server.is_some() || config.servers.is_some() {
+        match &config.servers {
+            Some(servers) => {
+                let mut ids: Vec<&u8> = servers.keys().collect();
+                ids.sort();
+                println!("Modbus Servers (IDs: {:?})", ids);
             }
-            ProtocolType::RTU => {
-                println!(
-                    "@ {}",
-                    config
-                        .common
-                        .serial_port
-                        .as_ref()
-                        .unwrap()
-                );
+            None => println!("Modbus Server (ID: {})", config.common.device_id),
+        }
+        for endpoint in config.common.endpoints() {
+            print!("runs {:?} ", endpoint.protocol_type);
+            match endpoint.protocol_type {
+                ProtocolType::TCP | ProtocolType::RtuOverTcp => {
+                    println!("@ {}", endpoint.ip_address.unwrap());
+                }
+                ProtocolType::RTU | ProtocolType::Ascii => {
+                    println!("@ {}", endpoint.serial_port.as_ref().unwrap());
+                }
             }
         }
     }
@@ -40,68 +41,213 @@ pub fn vprintln(s: &str, v: bool) {
     }
 }
 
-pub fn write_u16_into_f32(src: &[u16], e: EndiannessType) -> f32 {
-    let data: u32 = match e {
-        EndiannessType::LittleEndian => src[0] as u32 | ((src[1] as u32) << 16),
-        EndiannessType::BigEndian => src[1] as u32 | ((src[0] as u32) << 16),
+/// converts registers between the wire layout and the most-significant-word-first layout;
+/// the conversion is its own inverse
+pub fn reorder_u16(src: &[u16], e: EndiannessType) -> Vec<u16> {
+    let mut output: Vec<u16> = if e.is_byte_swapped() {
+        src.iter()
+            .map(|w| w.swap_bytes())
+            .collect()
+    } else {
+        src.to_vec()
     };
+    if e.is_word_swapped() {
+        output.reverse();
+    }
+    output
+}
 
-    unsafe { transmute(data) }
+pub fn write_u16_into_u32(src: &[u16], e: EndiannessType) -> u32 {
+    reorder_u16(&src[..2], e)
+        .iter()
+        .fold(0, |acc, w| acc << 16 | *w as u32)
 }
 
-pub fn write_u16_into_f64(src: &[u16], e: EndiannessType) -> f64 {
-    let data: u64 = match e {
-        EndiannessType::LittleEndian => src[0] as u64 | ((src[1] as u64) << 16) | ((src[2] as u64) << 32) | ((src[3] as u64) << 48),
-        EndiannessType::BigEndian => src[3] as u64 | ((src[2] as u64) << 16) | ((src[1] as u64) << 32) | ((src[0] as u64) << 48),
-    };
-    unsafe { transmute(data) }
+pub fn write_u16_into_u64(src: &[u16], e: EndiannessType) -> u64 {
+    reorder_u16(&src[..4], e)
+        .iter()
+        .fold(0, |acc, w| acc << 16 | *w as u64)
 }
 
-pub fn write_u16_into_u32(src: &[u16], e: EndiannessType) -> u32 {
-    let data: u32 = match e {
-        EndiannessType::LittleEndian => src[0] as u32 | (src[1] as u32) << 16,
-        EndiannessType::BigEndian => src[1] as u32 | (src[0] as u32) << 16,
+/// packs bytes into registers, the first byte taking the high byte of the first register;
+/// the output is padded with zeros up to `len` registers
+pub fn write_bytes_into_u16(src: &[u8], len: usize, e: EndiannessType) -> Vec<u16> {
+    let mut output: Vec<u16> = src
+        .chunks(2)
+        .map(|c| (c[0] as u16) << 8 | *c.get(1).unwrap_or(&0) as u16)
+        .collect();
+    output.resize(len.max(output.len()), 0);
+    if e.is_byte_swapped() {
+        output
+            .iter_mut()
+            .for_each(|w| *w = w.swap_bytes());
+    }
+    output
+}
+
+pub fn write_u16_into_bytes(src: &[u16], e: EndiannessType) -> Vec<u8> {
+    src.iter()
+        .flat_map(|w| {
+            if e.is_byte_swapped() {
+                w.to_le_bytes()
+            } else {
+                w.to_be_bytes()
+            }
+        })
+        .collect()
+}
+
+pub fn write_u16_into_string(src: &[u16], e: EndiannessType) -> String {
+    let bytes = write_u16_into_bytes(src, e);
+    let end = bytes
+        .iter()
+        .rposition(|b| *b != 0)
+        .map_or(0, |p| p + 1);
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// parses bytes in hex, e.g. "0x0102ABCD" or "01 02 AB CD"
+pub fn parse_bytes(src: &str) -> Option<Vec<u8>> {
+    let src = src.trim();
+    let digits: String = src
+        .strip_prefix("0x")
+        .or_else(|| src.strip_prefix("0X"))
+        .unwrap_or(src)
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '_' && *c != ':')
+        .collect();
+    if !digits.is_ascii() || !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect()
+}
+
+pub fn format_bytes(src: &[u8]) -> String {
+    src.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// packs the lowest `len` words of a bit field into registers
+pub fn write_bits_into_u16(src: u64, len: usize, e: EndiannessType) -> Vec<u16> {
+    let words: Vec<u16> = (0..len)
+        .rev()
+        .map(|i| ((src >> (16 * i)) & 0xFFFF) as u16)
+        .collect();
+    reorder_u16(&words, e)
+}
+
+pub fn write_u16_into_bits(src: &[u16], e: EndiannessType) -> u64 {
+    reorder_u16(src, e)
+        .iter()
+        .fold(0, |acc, w| acc << 16 | *w as u64)
+}
+
+/// formats a computed float without the noise of binary rounding, e.g. 23.400000000000002 as 23.4
+pub fn format_float(src: f64) -> String {
+    let s = format!("{:.9}", src);
+    let s = s
+        .trim_end_matches('0')
+        .trim_end_matches('.');
+    if s == "-0" {
+        "0".to_string()
+    } else {
+        s.to_string()
+    }
+}
+
+/// parses a number written in decimal, or as an integer in hex/binary/octal
+pub fn parse_number(src: &str) -> Option<f64> {
+    let src = src.trim();
+    src.parse::<f64>().ok().or_else(|| {
+        parse_int::parse::<i64>(src)
+            .ok()
+            .map(|v| v as f64)
+    })
+}
+
+/// parses a pair of values separated by a comma, e.g. "12, -3" or "0x0C, 0b11"
+pub fn parse_pair<T: TryFrom<i64>>(src: &str) -> Option<(T, T)> {
+    let parse = |v: &str| {
+        parse_int::parse::<i64>(v)
+            .ok()
+            .and_then(|v| T::try_from(v).ok())
     };
-    data
+    let (first, second) = src.split_once(',')?;
+    Some((parse(first)?, parse(second)?))
+}
+
+pub fn write_u8_pair_into_u16(src: (u8, u8), e: EndiannessType) -> Vec<u16> {
+    reorder_u16(&[(src.0 as u16) << 8 | src.1 as u16], e)
+}
+
+pub fn write_u16_into_u8_pair(src: &[u16], e: EndiannessType) -> (u8, u8) {
+    let data = reorder_u16(&src[..1], e)[0];
+    ((data >> 8) as u8, (data & 0xFF) as u8)
+}
+
+pub fn write_u16_into_i16(src: &[u16], e: EndiannessType) -> i16 {
+    reorder_u16(&src[..1], e)[0] as i16
+}
+
+pub fn write_i16_into_u16(src: i16, e: EndiannessType) -> Vec<u16> {
+    reorder_u16(&[src as u16], e)
+}
+
+pub fn write_u16_into_i32(src: &[u16], e: EndiannessType) -> i32 {
+    write_u16_into_u32(src, e) as i32
+}
+
+pub fn write_u16_into_i64(src: &[u16], e: EndiannessType) -> i64 {
+    write_u16_into_u64(src, e) as i64
+}
+
+pub fn write_u16_into_f32(src: &[u16], e: EndiannessType) -> f32 {
+    f32::from_bits(write_u16_into_u32(src, e))
+}
+
+pub fn write_u16_into_f64(src: &[u16], e: EndiannessType) -> f64 {
+    f64::from_bits(write_u16_into_u64(src, e))
+}
+
+pub fn write_u32_into_u16(src: u32, e: EndiannessType) -> Vec<u16> {
+    reorder_u16(&[(src >> 16) as u16, (src & 0xFFFF) as u16], e)
+}
+
+pub fn write_u64_into_u16(src: u64, e: EndiannessType) -> Vec<u16> {
+    reorder_u16(
+        &[
+            ((src >> 48) & 0xFFFF) as u16,
+            ((src >> 32) & 0xFFFF) as u16,
+            ((src >> 16) & 0xFFFF) as u16,
+            (src & 0xFFFF) as u16,
+        ],
+        e,
+    )
+}
+
+pub fn write_i32_into_u16(src: i32, e: EndiannessType) -> Vec<u16> {
+    write_u32_into_u16(src as u32, e)
+}
+
+pub fn write_i64_into_u16(src: i64, e: EndiannessType) -> Vec<u16> {
+    write_u64_into_u16(src as u64, e)
 }
 
 pub fn write_f32_into_u16(src: f32, e: EndiannessType) -> Vec<u16> {
-    let data: u32 = unsafe { transmute(src) };
-    let mut output = Vec::<u16>::new();
-    match e {
-        EndiannessType::LittleEndian => {
-            output.push((data & 0xFFFF) as u16);
-            output.push((data >> 16) as u16);
-        },
-        EndiannessType::BigEndian => {
-            output.push((data >> 16) as u16);
-            output.push((data & 0xFFFF) as u16);
-        },
-    };
-    output
+    write_u32_into_u16(src.to_bits(), e)
 }
 
 pub fn write_f64_into_u16(src: f64, e: EndiannessType) -> Vec<u16> {
-    let data: u64 = unsafe { transmute(src) };
-    let mut output = Vec::<u16>::new();
-    match e {
-        EndiannessType::LittleEndian => {
-            output.push((data & 0xFFFF) as u16);
-            output.push(((data >> 16) & 0xFFFF) as u16);
-            output.push(((data >> 32) & 0xFFFF) as u16);
-            output.push(((data >> 48) & 0xFFFF) as u16);
-        },
-        EndiannessType::BigEndian => {
-            output.push(((data >> 48) & 0xFFFF) as u16);
-            output.push(((data >> 32) & 0xFFFF) as u16);
-            output.push(((data >> 16) & 0xFFFF) as u16);
-            output.push((data & 0xFFFF) as u16);
-        },
-    };
-    output
+    write_u64_into_u16(src.to_bits(), e)
 }
 
 #[cfg(test)]
+#[allow(clippy::approx_constant)]
 mod tests {
     use super::*;
     use float_cmp::assert_approx_eq;
@@ -109,19 +255,30 @@ mod tests {
     #[test]
     fn given_2_u16_then_get_correct_f32() {
         let src = [0x0e56, 0x4049];
-        assert_approx_eq!(f32, 3.1415_f32, write_u16_into_f32(&src, EndiannessType::LittleEndian));
+        assert_approx_eq!(
+            f32,
+            3.1415_f32,
+            write_u16_into_f32(&src, EndiannessType::LittleEndian)
+        );
     }
 
     #[test]
     fn given4_u16_then_get_correct_f64() {
         let src = [0x2D18, 0x5444, 0x21FB, 0x4009];
-        assert_approx_eq!(f64, 3.141592653589793_f64, write_u16_into_f64(&src, EndiannessType::LittleEndian));
+        assert_approx_eq!(
+            f64,
+            3.141592653589793_f64,
+            write_u16_into_f64(&src, EndiannessType::LittleEndian)
+        );
     }
 
     #[test]
     fn given_f32_then_get_correct_2_u16() {
         let src = 3.1415_f32;
-        assert_eq!(vec![0x0e56, 0x4049], write_f32_into_u16(src, EndiannessType::LittleEndian));
+        assert_eq!(
+            vec![0x0e56, 0x4049],
+            write_f32_into_u16(src, EndiannessType::LittleEndian)
+        );
     }
 
     #[test]
@@ -132,4 +289,223 @@ mod tests {
             write_f64_into_u16(src, EndiannessType::LittleEndian)
         );
     }
+
+    #[test]
+    fn given_2_u16_then_get_correct_i32() {
+        let src = [0xFFFF, 0xFFFE];
+        assert_eq!(-2_i32, write_u16_into_i32(&src, EndiannessType::BigEndian));
+        assert_eq!(
+            -65537_i32,
+            write_u16_into_i32(&src, EndiannessType::LittleEndian)
+        );
+    }
+
+    #[test]
+    fn given_i32_then_get_correct_2_u16() {
+        assert_eq!(
+            vec![0xFFFF, 0xFFFE],
+            write_i32_into_u16(-2, EndiannessType::BigEndian)
+        );
+        assert_eq!(
+            vec![0xFFFE, 0xFFFF],
+            write_i32_into_u16(-2, EndiannessType::LittleEndian)
+        );
+    }
+
+    #[test]
+    fn given_4_u16_then_get_correct_u64() {
+        let src = [0x0123, 0x4567, 0x89AB, 0xCDEF];
+        assert_eq!(
+            0x0123_4567_89AB_CDEF_u64,
+            write_u16_into_u64(&src, EndiannessType::BigEndian)
+        );
+        assert_eq!(
+            0xCDEF_89AB_4567_0123_u64,
+            write_u16_into_u64(&src, EndiannessType::LittleEndian)
+        );
+    }
+
+    #[test]
+    fn given_u64_then_get_correct_4_u16() {
+        let src = 0x0123_4567_89AB_CDEF_u64;
+        assert_eq!(
+            vec![0x0123, 0x4567, 0x89AB, 0xCDEF],
+            write_u64_into_u16(src, EndiannessType::BigEndian)
+        );
+        assert_eq!(
+            vec![0xCDEF, 0x89AB, 0x4567, 0x0123],
+            write_u64_into_u16(src, EndiannessType::LittleEndian)
+        );
+    }
+
+    #[test]
+    fn given_4_u16_then_get_correct_i64() {
+        let src = [0xFFFF, 0xFFFF, 0xFFFF, 0xFF85];
+        assert_eq!(
+            -123_i64,
+            write_u16_into_i64(&src, EndiannessType::BigEndian)
+        );
+    }
+
+    #[test]
+    fn given_i64_then_get_correct_4_u16() {
+        assert_eq!(
+            vec![0xFF85, 0xFFFF, 0xFFFF, 0xFFFF],
+            write_i64_into_u16(-123, EndiannessType::LittleEndian)
+        );
+    }
+
+    #[test]
+    fn given_f32_then_get_correct_2_u16_in_all_layouts() {
+        let src = f32::from_bits(0xAABBCCDD);
+        assert_eq!(
+            vec![0xAABB, 0xCCDD],
+            write_f32_into_u16(src, EndiannessType::BigEndian)
+        );
+        assert_eq!(
+            vec![0xCCDD, 0xAABB],
+            write_f32_into_u16(src, EndiannessType::LittleEndian)
+        );
+        assert_eq!(
+            vec![0xBBAA, 0xDDCC],
+            write_f32_into_u16(src, EndiannessType::BigEndianByteSwap)
+        );
+        assert_eq!(
+            vec![0xDDCC, 0xBBAA],
+            write_f32_into_u16(src, EndiannessType::LittleEndianByteSwap)
+        );
+    }
+
+    #[test]
+    fn given_2_u16_in_all_layouts_then_get_correct_u32() {
+        assert_eq!(
+            0xAABBCCDD,
+            write_u16_into_u32(&[0xAABB, 0xCCDD], EndiannessType::BigEndian)
+        );
+        assert_eq!(
+            0xAABBCCDD,
+            write_u16_into_u32(&[0xCCDD, 0xAABB], EndiannessType::LittleEndian)
+        );
+        assert_eq!(
+            0xAABBCCDD,
+            write_u16_into_u32(&[0xBBAA, 0xDDCC], EndiannessType::BigEndianByteSwap)
+        );
+        assert_eq!(
+            0xAABBCCDD,
+            write_u16_into_u32(&[0xDDCC, 0xBBAA], EndiannessType::LittleEndianByteSwap)
+        );
+    }
+
+    #[test]
+    fn given_u64_then_get_correct_4_u16_with_byte_swap() {
+        let src = 0x0123_4567_89AB_CDEF_u64;
+        assert_eq!(
+            vec![0x2301, 0x6745, 0xAB89, 0xEFCD],
+            write_u64_into_u16(src, EndiannessType::BigEndianByteSwap)
+        );
+        assert_eq!(
+            vec![0xEFCD, 0xAB89, 0x6745, 0x2301],
+            write_u64_into_u16(src, EndiannessType::LittleEndianByteSwap)
+        );
+    }
+
+    #[test]
+    fn given_4_u16_with_byte_swap_then_get_correct_f64() {
+        let src = [0x182D, 0x4454, 0xFB21, 0x0940];
+        assert_approx_eq!(
+            f64,
+            std::f64::consts::PI,
+            write_u16_into_f64(&src, EndiannessType::LittleEndianByteSwap)
+        );
+    }
+
+    #[test]
+    fn given_string_then_get_correct_u16() {
+        let src = "ABC".as_bytes();
+        assert_eq!(
+            vec![0x4142, 0x4300, 0x0000],
+            write_bytes_into_u16(src, 3, EndiannessType::BigEndian)
+        );
+        assert_eq!(
+            vec![0x4241, 0x0043],
+            write_bytes_into_u16(src, 0, EndiannessType::BigEndianByteSwap)
+        );
+    }
+
+    #[test]
+    fn given_u16_then_get_correct_string() {
+        assert_eq!(
+            "ABC",
+            write_u16_into_string(&[0x4142, 0x4300, 0x0000], EndiannessType::BigEndian)
+        );
+        assert_eq!(
+            "ABC",
+            write_u16_into_string(&[0x4241, 0x0043], EndiannessType::LittleEndianByteSwap)
+        );
+    }
+
+    #[test]
+    fn given_hex_string_then_get_correct_bytes() {
+        assert_eq!(Some(vec![0x01, 0x02, 0xAB]), parse_bytes("0x0102AB"));
+        assert_eq!(Some(vec![0x01, 0x02, 0xAB]), parse_bytes("01 02 ab"));
+        assert_eq!(None, parse_bytes("0x012"));
+        assert_eq!("01 02 AB", format_bytes(&[0x01, 0x02, 0xAB]));
+    }
+
+    #[test]
+    fn given_bit_field_then_get_correct_u16() {
+        assert_eq!(
+            vec![0x0001, 0x8000],
+            write_bits_into_u16(0x0001_8000, 2, EndiannessType::BigEndian)
+        );
+        assert_eq!(
+            vec![0x8000, 0x0001],
+            write_bits_into_u16(0x0001_8000, 2, EndiannessType::LittleEndian)
+        );
+        assert_eq!(
+            0x0001_8000,
+            write_u16_into_bits(&[0x8000, 0x0001], EndiannessType::LittleEndian)
+        );
+    }
+
+    #[test]
+    fn given_computed_float_then_format_without_noise() {
+        assert_eq!("23.4", format_float(234_f64 * 0.1));
+        assert_eq!("-40", format_float(-40.0));
+        assert_eq!("0", format_float(-0.0000000001));
+    }
+
+    #[test]
+    fn given_i16_then_round_trip_through_u16() {
+        assert_eq!(
+            vec![0xFFF6],
+            write_i16_into_u16(-10, EndiannessType::BigEndian)
+        );
+        assert_eq!(
+            vec![0xF6FF],
+            write_i16_into_u16(-10, EndiannessType::BigEndianByteSwap)
+        );
+        assert_eq!(
+            -10,
+            write_u16_into_i16(&[0xF6FF], EndiannessType::LittleEndianByteSwap)
+        );
+    }
+
+    #[test]
+    fn given_8_bit_pair_then_round_trip_through_u16() {
+        assert_eq!(Some((12_u8, 34_u8)), parse_pair::<u8>("12, 34"));
+        assert_eq!(Some((-1_i8, 5_i8)), parse_pair::<i8>("-1,5"));
+        assert_eq!(Some((0x0C_u8, 0xFF_u8)), parse_pair::<u8>("0x0C, 0xFF"));
+        assert_eq!(Some((-1_i8, 3_i8)), parse_pair::<i8>("-1, 0b11"));
+        assert_eq!(None, parse_pair::<u8>("12"));
+        assert_eq!(None, parse_pair::<u8>("0x100, 1"));
+        assert_eq!(
+            vec![0x0C22],
+            write_u8_pair_into_u16((12, 34), EndiannessType::BigEndian)
+        );
+        assert_eq!(
+            (12, 34),
+            write_u16_into_u8_pair(&[0x220C], EndiannessType::BigEndianByteSwap)
+        );
+    }
 }
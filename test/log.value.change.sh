@@ -0,0 +1,3 @@
+#!/bin/sh
+# logs a change of a register/coil value, given its address, the old value and the new value
+echo "$(date +%T) @ $1: $2 -> $3" >> ./test/value.changes.log
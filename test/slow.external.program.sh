@@ -0,0 +1,3 @@
+#!/bin/sh
+# an external program taking its time, as a slow device logic would
+sleep 1
@@ -0,0 +1,10 @@
+#!/usr/bin/env python3
+import json
+import sys
+
+# set the power led state with the value of power led button, whenever the button is written
+for line in sys.stdin:
+    event = json.loads(line)
+    if event['event'] == 'write' and '10006' in event['coils']:
+        command = {'command': 'set_coil', 'address': 10005, 'value': event['coils']['10006']}
+        print(json.dumps(command), flush=True)